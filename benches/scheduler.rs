@@ -0,0 +1,196 @@
+//! Compares the work-stealing scheduler with the original design, where all
+//! workers share one `Mutex<mpsc::Receiver>`.
+//!
+//! Run with `cargo bench`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use threadpool::ThreadPool;
+
+const THREADS: usize = 8;
+const ROUNDS: u32 = 10;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The thread pool from chapter 20 of "The Rust Programming Language".
+struct ChannelPool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ChannelPool {
+    fn new(thread_count: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..thread_count)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool {
+            sender: Some(sender),
+            threads,
+        }
+    }
+
+    fn spawner(&self) -> mpsc::Sender<Job> {
+        self.sender.as_ref().unwrap().clone()
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Blocks until `count_down` has been called `count` times.
+struct Latch {
+    remaining: Mutex<usize>,
+    done: Condvar,
+}
+
+impl Latch {
+    fn new(count: usize) -> Arc<Latch> {
+        Arc::new(Latch {
+            remaining: Mutex::new(count),
+            done: Condvar::new(),
+        })
+    }
+
+    fn count_down(&self) {
+        let mut remaining = self.remaining.lock().unwrap();
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut remaining = self.remaining.lock().unwrap();
+        while *remaining > 0 {
+            remaining = self.done.wait(remaining).unwrap();
+        }
+    }
+}
+
+/// A few hundred nanoseconds of work.
+fn work(sum: &AtomicUsize) {
+    let mut x = 0usize;
+    for i in 0..100 {
+        x = x.wrapping_mul(31).wrapping_add(i);
+    }
+    sum.fetch_add(x & 1, Ordering::Relaxed);
+}
+
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    f();
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        f();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<40} {:>10.2?}", name, best);
+}
+
+/// Submits `jobs` small jobs from the main thread.
+fn flat(jobs: usize) {
+    let sum = Arc::new(AtomicUsize::new(0));
+
+    let pool = ThreadPool::new(THREADS);
+    bench(&format!("flat/{}/work-stealing", jobs), || {
+        let latch = Latch::new(jobs);
+        for _ in 0..jobs {
+            let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+            pool.execute(move || {
+                work(&sum);
+                latch.count_down();
+            });
+        }
+        latch.wait();
+    });
+
+    let pool = ChannelPool::new(THREADS);
+    let spawner = pool.spawner();
+    bench(&format!("flat/{}/channel", jobs), || {
+        let latch = Latch::new(jobs);
+        for _ in 0..jobs {
+            let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+            spawner
+                .send(Box::new(move || {
+                    work(&sum);
+                    latch.count_down();
+                }))
+                .unwrap();
+        }
+        latch.wait();
+    });
+}
+
+/// Submits `outer` jobs from the main thread that each spawn `inner` small
+/// jobs from inside the pool.
+fn nested(outer: usize, inner: usize) {
+    let sum = Arc::new(AtomicUsize::new(0));
+
+    let pool: &'static ThreadPool = Box::leak(Box::new(ThreadPool::new(THREADS)));
+    bench(&format!("nested/{}x{}/work-stealing", outer, inner), || {
+        let latch = Latch::new(outer * inner);
+        for _ in 0..outer {
+            let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+            pool.execute(move || {
+                for _ in 0..inner {
+                    let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+                    pool.execute(move || {
+                        work(&sum);
+                        latch.count_down();
+                    });
+                }
+            });
+        }
+        latch.wait();
+    });
+
+    let pool = ChannelPool::new(THREADS);
+    let spawner = pool.spawner();
+    bench(&format!("nested/{}x{}/channel", outer, inner), || {
+        let latch = Latch::new(outer * inner);
+        for _ in 0..outer {
+            let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+            let inner_spawner = spawner.clone();
+            spawner
+                .send(Box::new(move || {
+                    for _ in 0..inner {
+                        let (latch, sum) = (Arc::clone(&latch), Arc::clone(&sum));
+                        inner_spawner
+                            .send(Box::new(move || {
+                                work(&sum);
+                                latch.count_down();
+                            }))
+                            .unwrap();
+                    }
+                }))
+                .unwrap();
+        }
+        latch.wait();
+    });
+}
+
+fn main() {
+    flat(100_000);
+    nested(1_000, 100);
+}
@@ -0,0 +1,324 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::Metrics;
+use crate::scaling::Scaling;
+use crate::scheduler::Scheduler;
+use crate::tenant::Tenants;
+use crate::timer::Timer;
+use crate::watchdog::Watchdog;
+use crate::worker::{ThreadConfig, Workers};
+use crate::{Shared, ThreadPool, ThreadPoolError};
+
+/// What happens to a job that is submitted while the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Block the submitter until there is room in the queue.
+    Block,
+    /// Hand the job back to the submitter. `ThreadPool::execute` panics,
+    /// `ThreadPool::try_execute` returns the job in an error.
+    Reject,
+    /// Drop the oldest queued job to make room for the new one. Jobs spawned
+    /// in a `ThreadPool::scope`, or by the parallel helpers, `join` and job
+    /// graphs, are never dropped. If only such jobs are queued, the submitter
+    /// blocks until there is room.
+    DropOldest,
+    /// Run the job on the submitting thread.
+    CallerRuns,
+}
+
+/// Configures and creates a `ThreadPool`.
+pub struct ThreadPoolBuilder {
+    thread_count: Option<usize>,
+    min_threads: Option<usize>,
+    max_threads: Option<usize>,
+    keep_alive: Duration,
+    queue_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    priority_aging: Option<Duration>,
+    /// The names and weights of the queues, starting with the default queue.
+    queues: Vec<(String, u32)>,
+    job_timings: bool,
+    replace_stuck_workers: bool,
+    thread_config: ThreadConfig,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Creates a builder for a pool with one thread per CPU and an unbounded
+    /// queue.
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            thread_count: None,
+            min_threads: None,
+            max_threads: None,
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: None,
+            overflow_policy: OverflowPolicy::Block,
+            priority_aging: None,
+            queues: vec![("default".to_string(), 1)],
+            job_timings: false,
+            replace_stuck_workers: false,
+            thread_config: ThreadConfig::default(),
+        }
+    }
+
+    /// Sets the number of threads in the pool. With autoscaling, this is
+    /// the number of threads the pool starts with, limited to the range
+    /// between `min_threads` and `max_threads`.
+    pub fn thread_count(mut self, thread_count: usize) -> ThreadPoolBuilder {
+        self.thread_count = Some(thread_count);
+        self
+    }
+
+    /// Lets the pool start more threads, up to `max` in total, while jobs are
+    /// queued and all threads are busy. Threads beyond `min_threads` stop
+    /// again once they have been idle for `keep_alive`.
+    ///
+    /// The pool does not scale by default.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_threads = Some(max);
+        self
+    }
+
+    /// Sets the number of threads an autoscaling pool keeps when it is idle.
+    /// It can be zero. The default is the thread count. Has no effect without
+    /// `max_threads`.
+    pub fn min_threads(mut self, min: usize) -> ThreadPoolBuilder {
+        self.min_threads = Some(min);
+        self
+    }
+
+    /// Sets how long a thread of an autoscaling pool waits for work before
+    /// it stops. The default is 60 seconds. Has no effect without
+    /// `max_threads`.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Limits the number of jobs waiting in the queue to `capacity`.
+    ///
+    /// Only jobs submitted from outside the pool count towards the limit.
+    /// Jobs submitted by jobs running on the pool are always queued.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets what happens to jobs submitted while the queue is full. The
+    /// default is `OverflowPolicy::Block`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Raises the priority of a queued job by one level for every `interval`
+    /// it waits, so that low priority jobs are not starved by a steady stream
+    /// of more urgent ones. Jobs do not age by default.
+    pub fn priority_aging(mut self, interval: Duration) -> ThreadPoolBuilder {
+        self.priority_aging = Some(interval);
+        self
+    }
+
+    /// Adds a queue named `name` with the given weight, or changes the
+    /// weight of an existing queue. Jobs are submitted to named queues with
+    /// `ThreadPool::execute_in` and `ThreadPool::submit_in`, all other jobs
+    /// go into the queue named `"default"`, which has a weight of 1.
+    ///
+    /// While several queues have jobs of the same priority waiting, workers
+    /// take jobs from them in proportion to their weights. With weights 4 and
+    /// 1, for example, four jobs of the first queue run for every job of the
+    /// second, so a flood of jobs in one queue cannot starve the others. All
+    /// queues share the pool's threads and its queue capacity.
+    pub fn queue<S: Into<String>>(mut self, name: S, weight: u32) -> ThreadPoolBuilder {
+        let name = name.into();
+        match self.queues.iter_mut().find(|(other, _)| *other == name) {
+            Some(queue) => queue.1 = weight,
+            None => self.queues.push((name, weight)),
+        }
+        self
+    }
+
+    /// Records how long every job waits in the queue and how long it runs,
+    /// for the histograms returned by `ThreadPool::stats`, and how long each
+    /// worker is busy. This reads the clock up to three times per job, and
+    /// whenever a worker starts or stops waiting for work, so it is off by
+    /// default.
+    pub fn job_timings(mut self, enabled: bool) -> ThreadPoolBuilder {
+        self.job_timings = enabled;
+        self
+    }
+
+    /// Starts another worker in place of a worker whose job runs past its
+    /// timeout, see `ThreadPool::submit_with_timeout`, so the pool keeps its
+    /// number of threads while the job is stuck. The stuck worker stops once
+    /// the job returns, if it ever does. Off by default.
+    pub fn replace_stuck_workers(mut self, enabled: bool) -> ThreadPoolBuilder {
+        self.replace_stuck_workers = enabled;
+        self
+    }
+
+    /// Names the threads `"{prefix}-{id}"`, where `id` is the id of the
+    /// worker running on the thread. Threads are unnamed by default.
+    pub fn thread_name_prefix<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.thread_config.name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets the stack size of the threads, in bytes. The default is the
+    /// default of `std::thread`.
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.thread_config.stack_size = Some(size);
+        self
+    }
+
+    /// Sets a callback that every thread runs when it starts, before running
+    /// any jobs. It receives the id of the worker running on the thread.
+    pub fn on_thread_start<H>(mut self, hook: H) -> ThreadPoolBuilder
+    where
+        H: Fn(usize) + Send + Sync + 'static,
+    {
+        self.thread_config.on_start = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets a callback that every thread runs right before it terminates. It
+    /// receives the id of the worker running on the thread.
+    pub fn on_thread_stop<H>(mut self, hook: H) -> ThreadPoolBuilder
+    where
+        H: Fn(usize) + Send + Sync + 'static,
+    {
+        self.thread_config.on_stop = Some(Box::new(hook));
+        self
+    }
+
+    /// Creates the pool.
+    ///
+    /// Returns an error if the thread count or the queue capacity is zero, if
+    /// `min_threads` is greater than `max_threads`, if a queue has a weight
+    /// of zero, or if a thread cannot be spawned. The threads that were
+    /// already spawned are shut down again.
+    pub fn build(self) -> Result<ThreadPool, ThreadPoolError> {
+        let mut thread_count = self
+            .thread_count
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()));
+        let scaling = match self.max_threads {
+            Some(max) => {
+                let min = self.min_threads.unwrap_or_else(|| thread_count.min(max));
+                if max == 0 {
+                    return Err(ThreadPoolError::ZeroThreads);
+                }
+                if min > max {
+                    return Err(ThreadPoolError::InvalidThreadRange { min, max });
+                }
+                thread_count = thread_count.clamp(min, max);
+                Some(Scaling {
+                    min,
+                    max,
+                    keep_alive: self.keep_alive,
+                })
+            }
+            None if thread_count == 0 => return Err(ThreadPoolError::ZeroThreads),
+            None => None,
+        };
+        if self.queue_capacity == Some(0) {
+            return Err(ThreadPoolError::ZeroCapacity);
+        }
+        if let Some((name, _)) = self.queues.iter().find(|&&(_, weight)| weight == 0) {
+            return Err(ThreadPoolError::ZeroWeight {
+                queue: name.clone(),
+            });
+        }
+
+        let shared = Arc::new(Shared {
+            scheduler: Scheduler::new(
+                self.queue_capacity,
+                self.priority_aging,
+                self.job_timings,
+                self.queues,
+            ),
+            timer: Timer::new("timer"),
+            watchdog: Watchdog::new("watchdog"),
+            workers: Mutex::new(Workers::new()),
+            scaling,
+            thread_config: self.thread_config,
+            metrics: Metrics::new(self.job_timings),
+            tenants: Tenants::default(),
+            panic_handler: RwLock::new(None),
+            timeout_handler: RwLock::new(None),
+            replace_stuck_workers: self.replace_stuck_workers,
+        });
+
+        let mut pool = ThreadPool {
+            overflow_policy: self.overflow_policy,
+            shared,
+        };
+        // Create the threads:
+        pool.resize(thread_count)?;
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::{ThreadPoolBuilder, ThreadPoolError};
+
+    #[test]
+    fn threads_are_named_and_run_the_hooks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pool = ThreadPoolBuilder::new()
+            .thread_count(2)
+            .thread_name_prefix("io")
+            .stack_size(256 * 1024)
+            .on_thread_start({
+                let log = Arc::clone(&log);
+                move |id| log.lock().unwrap().push(format!("start {}", id))
+            })
+            .on_thread_stop({
+                let log = Arc::clone(&log);
+                move |id| log.lock().unwrap().push(format!("stop {}", id))
+            })
+            .build()
+            .unwrap();
+        let name = pool
+            .submit(|| thread::current().name().map(str::to_string))
+            .join()
+            .unwrap();
+        assert!(matches!(name.as_deref(), Some("io-1" | "io-2")));
+
+        pool.set_thread_count(1).unwrap();
+        assert!(log.lock().unwrap().contains(&"stop 2".to_string()));
+        drop(pool);
+        let mut log = log.lock().unwrap().clone();
+        log.sort();
+        assert_eq!(log, ["start 1", "start 2", "stop 1", "stop 2"]);
+    }
+
+    #[test]
+    fn panicking_start_hook_is_reported_on_shutdown() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(2)
+            .on_thread_start(|id| {
+                if id == 1 {
+                    panic!("start hook panicked");
+                }
+            })
+            .build()
+            .unwrap();
+        assert_eq!(pool.submit(|| 1).join().unwrap(), 1);
+        assert!(matches!(
+            pool.shutdown(),
+            Err(ThreadPoolError::WorkerPanicked { id: 1 })
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use log::debug;
+
+use crate::{Shared, ThreadPoolError};
+
+/// Identifies an item in `Deadlines`. The second part tells apart items with
+/// the same deadline, and keeps them in the order they were inserted.
+pub(crate) type Key = (Instant, u64);
+
+/// Items with a deadline, and the thread that handles each item once its
+/// deadline has passed. Used by `Timer` and `Watchdog`. The thread is only
+/// started when it is first needed.
+pub(crate) struct Deadlines<T> {
+    /// Names the thread, after the pool's thread name prefix.
+    name: &'static str,
+    state: Mutex<State<T>>,
+    changed: Condvar,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+struct State<T> {
+    items: BTreeMap<Key, T>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+impl<T: Send + 'static> Deadlines<T> {
+    pub(crate) fn new(name: &'static str) -> Deadlines<T> {
+        Deadlines {
+            name,
+            state: Mutex::new(State {
+                items: BTreeMap::new(),
+                next_seq: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Starts the thread, unless it is running already or has stopped. The
+    /// thread finds the `Deadlines` in `shared` with `this`, and calls
+    /// `expire` with every item whose deadline has passed.
+    pub(crate) fn start(
+        &self,
+        shared: &Arc<Shared>,
+        this: fn(&Shared) -> &Deadlines<T>,
+        expire: fn(&Arc<Shared>, T),
+    ) -> Result<(), ThreadPoolError> {
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_none() && !self.state.lock().unwrap().stopped {
+            let mut builder = thread::Builder::new();
+            if let Some(prefix) = &shared.thread_config.name_prefix {
+                builder = builder.name(format!("{}-{}", prefix, self.name));
+            }
+            let shared = Arc::clone(shared);
+            *thread = Some(builder.spawn(move || run(shared, this, expire))?);
+        }
+        Ok(())
+    }
+
+    /// Adds `item` with `deadline`, and returns its key. Returns `None` once
+    /// the thread has stopped.
+    pub(crate) fn insert(&self, deadline: Instant, item: T) -> Option<Key> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return None;
+        }
+        let key = (deadline, state.next_seq);
+        state.next_seq += 1;
+        // Only an item that is due before all others changes how long the
+        // thread has to sleep.
+        if state.items.keys().next().is_none_or(|first| key < *first) {
+            self.changed.notify_one();
+        }
+        state.items.insert(key, item);
+        Some(key)
+    }
+
+    /// Removes the item with `key`, unless it has expired already.
+    pub(crate) fn remove(&self, key: Key) -> Option<T> {
+        self.state.lock().unwrap().items.remove(&key)
+    }
+
+    /// Drops all items that have not expired yet and stops the thread.
+    pub(crate) fn stop(&self) {
+        let items = {
+            let mut state = self.state.lock().unwrap();
+            state.stopped = true;
+            self.changed.notify_all();
+            mem::take(&mut state.items)
+        };
+        drop(items);
+
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            debug!("Waiting for the {} thread to terminate.", self.name);
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run<T>(shared: Arc<Shared>, this: fn(&Shared) -> &Deadlines<T>, expire: fn(&Arc<Shared>, T)) {
+    let deadlines = this(&shared);
+    let mut state = deadlines.state.lock().unwrap();
+    while !state.stopped {
+        let now = Instant::now();
+        let deadline = match state.items.keys().next() {
+            Some(&(deadline, _)) => deadline,
+            None => {
+                state = deadlines.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+        if deadline > now {
+            state = deadlines
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+            continue;
+        }
+
+        let (_, item) = state.items.pop_first().unwrap();
+        drop(state);
+        expire(&shared, item);
+        state = deadlines.state.lock().unwrap();
+    }
+}
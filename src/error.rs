@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The errors that can occur when creating, resizing or stopping a
+/// `ThreadPool`.
+#[derive(Debug)]
+pub enum ThreadPoolError {
+    /// The pool was configured without threads.
+    ZeroThreads,
+    /// The pool was configured with more `min_threads` than `max_threads`.
+    InvalidThreadRange { min: usize, max: usize },
+    /// The queue was configured with a capacity of zero.
+    ZeroCapacity,
+    /// A queue was configured with a weight of zero.
+    ZeroWeight { queue: String },
+    /// A thread could not be spawned.
+    Spawn(io::Error),
+    /// The pool is shutting down and does not accept new jobs.
+    ShuttingDown,
+    /// The thread of worker `id` panicked. Job panics are caught, so this
+    /// means a thread start or stop callback panicked.
+    WorkerPanicked { id: usize },
+    /// `ThreadPool::shutdown_timeout` ran out of time. Contains the ids of
+    /// the workers that were still running. Their threads are detached.
+    TimedOut { workers: Vec<usize> },
+}
+
+impl fmt::Display for ThreadPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadPoolError::ZeroThreads => write!(f, "thread pool needs at least one thread"),
+            ThreadPoolError::InvalidThreadRange { min, max } => write!(
+                f,
+                "min_threads ({}) is greater than max_threads ({})",
+                min, max
+            ),
+            ThreadPoolError::ZeroCapacity => write!(f, "queue capacity must not be zero"),
+            ThreadPoolError::ZeroWeight { queue } => {
+                write!(f, "queue {:?} has a weight of zero", queue)
+            }
+            ThreadPoolError::Spawn(err) => write!(f, "failed to spawn thread: {}", err),
+            ThreadPoolError::ShuttingDown => write!(f, "thread pool is shutting down"),
+            ThreadPoolError::WorkerPanicked { id } => write!(f, "worker {} panicked", id),
+            ThreadPoolError::TimedOut { workers } => {
+                write!(f, "workers {:?} did not stop in time", workers)
+            }
+        }
+    }
+}
+
+impl Error for ThreadPoolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ThreadPoolError::Spawn(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ThreadPoolError {
+    fn from(err: io::Error) -> ThreadPoolError {
+        ThreadPoolError::Spawn(err)
+    }
+}
+
+/// The error returned by `ThreadPool::try_execute`. Contains the job that
+/// could not be queued.
+pub enum TryExecuteError<F> {
+    /// The queue of the pool is full.
+    Full(F),
+    /// The pool is shutting down and does not accept new jobs.
+    ShuttingDown(F),
+}
+
+impl<F> TryExecuteError<F> {
+    /// Returns the job that could not be queued.
+    pub fn into_inner(self) -> F {
+        match self {
+            TryExecuteError::Full(f) | TryExecuteError::ShuttingDown(f) => f,
+        }
+    }
+
+    pub(crate) fn map<G>(self, op: impl FnOnce(F) -> G) -> TryExecuteError<G> {
+        match self {
+            TryExecuteError::Full(f) => TryExecuteError::Full(op(f)),
+            TryExecuteError::ShuttingDown(f) => TryExecuteError::ShuttingDown(op(f)),
+        }
+    }
+}
+
+impl<F> fmt::Debug for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => write!(f, "Full(..)"),
+            TryExecuteError::ShuttingDown(_) => write!(f, "ShuttingDown(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => write!(f, "thread pool queue is full"),
+            TryExecuteError::ShuttingDown(_) => write!(f, "thread pool is shutting down"),
+        }
+    }
+}
+
+impl<F> Error for TryExecuteError<F> {}
@@ -0,0 +1,460 @@
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::handle::Completer;
+use crate::metrics::Outcome;
+use crate::scheduler::{Overflow, Task};
+use crate::{trace, worker, JobError, JobHandle, JobStatus, Shared, ThreadPool};
+
+/// Waiting to be woken up.
+const IDLE: u8 = 0;
+/// Queued to be polled.
+const SCHEDULED: u8 = 1;
+/// Being polled.
+const RUNNING: u8 = 2;
+/// Woken up while it was polled, so it is queued again afterwards.
+const NOTIFIED: u8 = 3;
+/// Completed, cancelled or dropped. Wakeups are ignored.
+const DONE: u8 = 4;
+
+/// A future spawned with `ThreadPool::spawn_future`. Every poll runs as a
+/// job on the pool, and waking the future queues the next one.
+struct FutureTask<F: Future> {
+    state: AtomicU8,
+    /// The pool is not kept alive by wakers of its futures. Once it is gone,
+    /// a wakeup drops the future instead.
+    shared: Weak<Shared>,
+    /// Where the future was spawned from, for the spans of its polls.
+    context: trace::Context,
+    /// The future and the job's end of its handle, until it is done.
+    slot: Mutex<Option<Spawned<F>>>,
+}
+
+type Spawned<F> = (Pin<Box<F>>, Completer<<F as Future>::Output>);
+
+impl<F> FutureTask<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    /// Polls the future once, as a job on the pool.
+    fn run(self: Arc<Self>) {
+        if self
+            .state
+            .compare_exchange(SCHEDULED, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // Dropped while it was queued.
+            return;
+        }
+
+        let mut slot = self.slot.lock().unwrap();
+        let (future, completer) = match slot.as_mut() {
+            Some(slot) => slot,
+            None => return,
+        };
+        if !completer.resume() {
+            worker::report(Outcome::Cancelled);
+            drop(slot);
+            self.close();
+            return;
+        }
+
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+            Ok(Poll::Pending) => None,
+            Ok(Poll::Ready(value)) => Some(Ok(value)),
+            Err(payload) => {
+                worker::report(Outcome::Panicked);
+                Some(Err(payload))
+            }
+        };
+
+        match result {
+            Some(result) => {
+                let (future, completer) = slot.take().unwrap();
+                drop(slot);
+                self.state.store(DONE, Ordering::Release);
+                // The future may wake itself while it is dropped, which is
+                // ignored now that it is done.
+                drop(future);
+                completer.complete(result);
+            }
+            None => {
+                drop(slot);
+                if self
+                    .state
+                    .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // Woken up while it was polled.
+                    self.state.store(SCHEDULED, Ordering::Release);
+                    self.schedule();
+                }
+            }
+        }
+    }
+
+    /// Queues a poll of the future, which must be in the `SCHEDULED` state.
+    fn schedule(self: &Arc<Self>) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return self.close(),
+        };
+        let task = Arc::clone(self);
+        let task = Task {
+            context: self.context.clone(),
+            ..Task::new(move || task.run())
+        };
+        // Wakers must not block, so the poll is queued even if the queue is
+        // full. This fails if the pool is shutting down, and since no worker
+        // can take the poll, the future is dropped.
+        if shared.push(task, Overflow::Force).is_err() {
+            self.close();
+        }
+    }
+
+    /// Drops the future, which marks its handle as dropped unless it has
+    /// finished already.
+    fn close(&self) {
+        self.state.store(DONE, Ordering::Release);
+        let slot = self.slot.lock().unwrap().take();
+        drop(slot);
+    }
+}
+
+impl<F> Wake for FutureTask<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self
+                .state
+                .compare_exchange(state, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        if state == IDLE {
+            self.schedule();
+        }
+    }
+}
+
+/// A handle to a future spawned with `ThreadPool::spawn_future`. It is a
+/// future itself, which resolves to the output of the spawned future.
+///
+/// Dropping the handle does not cancel the future.
+pub struct JoinHandle<T> {
+    handle: JobHandle<T>,
+    /// Wakes the spawned future, see `cancel`.
+    task: Waker,
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancels the future. It is dropped instead of being polled again, and
+    /// the handle returns `JobError::Cancelled`.
+    ///
+    /// Returns `false` if the future had already finished.
+    pub fn cancel(&self) -> bool {
+        if !self.handle.abort() {
+            return false;
+        }
+        // Drops the future now rather than when it would have been woken up.
+        self.task.wake_by_ref();
+        true
+    }
+
+    /// Returns where the future is at, without blocking. It is running from
+    /// its first poll until it completes, also while it waits to be woken up.
+    pub fn status(&self) -> JobStatus {
+        self.handle.status()
+    }
+
+    /// Blocks until the future has finished and returns its output. This is
+    /// for synchronous code; async code awaits the handle instead.
+    pub fn join(self) -> Result<T, JobError> {
+        self.handle.join()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().handle.poll_join(cx)
+    }
+}
+
+impl ThreadPool {
+    /// Spawns `future` onto the pool and returns a handle to its output. If
+    /// the future panics, the panic payload is returned from the handle.
+    ///
+    /// The future is polled by the pool's workers, one poll per job, next to
+    /// the pool's other jobs. When it is woken up, the next poll is queued.
+    /// Polls should not block, or they hold up a worker just like a long
+    /// running job would. Polls queued by wakeups never block either: they
+    /// are queued even if the queue is full.
+    ///
+    /// Wakeups after the pool has shut down drop the future, and the handle
+    /// returns `JobError::Dropped`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn spawn_future<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // Futures are not queued with a token, so there is nothing to take
+        // out of the queue when they are cancelled.
+        let (completer, handle) = JobHandle::new(Weak::new());
+        let task = Arc::new(FutureTask {
+            state: AtomicU8::new(SCHEDULED),
+            shared: Arc::downgrade(&self.shared),
+            context: trace::Context::current(),
+            slot: Mutex::new(Some((Box::pin(future), completer))),
+        });
+        let waker = Waker::from(Arc::clone(&task));
+        self.execute(move || task.run());
+        JoinHandle {
+            handle,
+            task: waker,
+        }
+    }
+
+    /// Runs the blocking function `f` on the pool and returns a future of
+    /// its result, so async code can offload work without blocking its
+    /// executor. If `f` panics, the panic payload is returned as an error.
+    ///
+    /// The future is woken up when `f` has finished and works with any
+    /// executor. Dropping it does not cancel `f`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn spawn_blocking<F, T>(&self, f: F) -> impl Future<Output = Result<T, JobError>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut handle = self.submit(f);
+        future::poll_fn(move |cx| handle.poll_join(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+
+    use crate::{JobError, JobStatus, ThreadPool};
+
+    /// Wakes itself up the given number of times before it is ready.
+    struct Yield(usize);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// Ready once `open` has been called on one of its clones.
+    #[derive(Clone, Default)]
+    struct Gate(Arc<Mutex<(bool, Option<Waker>)>>);
+
+    impl Gate {
+        fn open(&self) {
+            let mut gate = self.0.lock().unwrap();
+            gate.0 = true;
+            if let Some(waker) = gate.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Future for Gate {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut gate = self.0.lock().unwrap();
+            if gate.0 {
+                return Poll::Ready(());
+            }
+            gate.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Counts how often it is dropped.
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn futures_are_polled_until_ready() {
+        let pool = ThreadPool::new(3);
+        let handles: Vec<_> = (0..100)
+            .map(|i| {
+                pool.spawn_future(async move {
+                    Yield(i % 7).await;
+                    i
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn woken_futures_are_polled_again() {
+        let pool = ThreadPool::new(2);
+        let gate = Gate::default();
+        let (started_tx, started_rx) = mpsc::channel();
+        let inner = pool.spawn_future({
+            let gate = gate.clone();
+            async move {
+                started_tx.send(()).unwrap();
+                gate.await;
+                7
+            }
+        });
+        started_rx.recv().unwrap();
+        assert_eq!(inner.status(), JobStatus::Running);
+        let outer = pool.spawn_future(async move { inner.await.unwrap() * 2 });
+        gate.open();
+        assert_eq!(outer.join().unwrap(), 14);
+    }
+
+    #[test]
+    fn panic_reaches_the_handle() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.spawn_future(async {
+            Yield(3).await;
+            panic!("future panicked");
+        });
+        assert!(matches!(handle.join(), Err(JobError::Panicked(_))));
+        assert_eq!(pool.spawn_future(async { 1 }).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn cancel_drops_a_waiting_future() {
+        let pool = ThreadPool::new(1);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+        let handle = pool.spawn_future({
+            let counter = DropCounter(Arc::clone(&dropped));
+            async move {
+                let _counter = counter;
+                started_tx.send(()).unwrap();
+                Gate::default().await;
+            }
+        });
+        started_rx.recv().unwrap();
+        assert!(handle.cancel());
+        pool.wait_idle();
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+        assert_eq!(pool.stats().cancelled, 1);
+    }
+
+    #[test]
+    fn wakeup_after_shutdown_drops_the_future() {
+        let pool = ThreadPool::new(1);
+        let gate = Gate::default();
+        let (started_tx, started_rx) = mpsc::channel();
+        let handle = pool.spawn_future({
+            let gate = gate.clone();
+            async move {
+                started_tx.send(()).unwrap();
+                gate.await;
+            }
+        });
+        started_rx.recv().unwrap();
+        drop(pool);
+        gate.open();
+        assert!(matches!(handle.join(), Err(JobError::Dropped)));
+    }
+
+    /// Unparks the thread that is waiting for a future, and counts how often
+    /// it did.
+    struct Unpark {
+        thread: thread::Thread,
+        wakeups: AtomicUsize,
+    }
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.wakeups.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    #[test]
+    fn spawn_blocking_wakes_the_awaiting_task_once() {
+        let pool = ThreadPool::new(1);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let mut future = Box::pin(pool.spawn_blocking(move || {
+            let _ = release_rx.recv();
+            5
+        }));
+        let unpark = Arc::new(Unpark {
+            thread: thread::current(),
+            wakeups: AtomicUsize::new(0),
+        });
+        let waker = Waker::from(Arc::clone(&unpark));
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        drop(release_tx);
+        while unpark.wakeups.load(Ordering::SeqCst) == 0 {
+            thread::park();
+        }
+        assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(5))));
+        assert_eq!(unpark.wakeups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn spawn_blocking_returns_panics() {
+        let pool = ThreadPool::new(1);
+        let result = pool
+            .spawn_future(pool.spawn_blocking(|| panic!("blocking job panicked")))
+            .join()
+            .unwrap();
+        assert!(matches!(result, Err(JobError::Panicked(_))));
+    }
+}
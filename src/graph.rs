@@ -0,0 +1,429 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::ops::Index;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::handle::panic_message;
+use crate::metrics::Outcome;
+use crate::{worker, Scope, ThreadPool};
+
+type NodeJob<T, E> = Box<dyn FnOnce(&[&T]) -> Result<T, E> + Send>;
+
+/// Identifies a node of a `JobGraph`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// Returns the position of the node in the order the nodes were added.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A graph of jobs, where each job runs once all of its parents have
+/// completed and receives their results. Run it with
+/// `ThreadPool::run_graph`.
+///
+/// Every job returns a `Result`, and an `Err` fails the whole graph, like a
+/// panic does.
+pub struct JobGraph<T, E> {
+    nodes: Vec<Node<T, E>>,
+}
+
+struct Node<T, E> {
+    job: NodeJob<T, E>,
+    parents: Vec<usize>,
+    children: Vec<usize>,
+}
+
+impl<T, E> JobGraph<T, E> {
+    /// Creates an empty graph.
+    pub fn new() -> JobGraph<T, E> {
+        JobGraph { nodes: Vec::new() }
+    }
+
+    /// Adds a node that runs `job`. The job receives the results of the
+    /// node's parents, in the order the edges to them were added.
+    pub fn add_node<F>(&mut self, job: F) -> NodeId
+    where
+        F: FnOnce(&[&T]) -> Result<T, E> + Send + 'static,
+    {
+        self.nodes.push(Node {
+            job: Box::new(job),
+            parents: Vec::new(),
+            children: Vec::new(),
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Makes `child` run after `parent` and receive its result. Adding an
+    /// edge twice has no effect.
+    ///
+    /// Returns an error, and leaves the graph as it was, if the edge would
+    /// create a cycle.
+    ///
+    /// # Panics
+    ///
+    /// This panics if either node is not part of this graph.
+    pub fn add_edge(&mut self, parent: NodeId, child: NodeId) -> Result<(), CycleError> {
+        assert!(
+            parent.0 < self.nodes.len() && child.0 < self.nodes.len(),
+            "node is not part of this graph"
+        );
+        if self.nodes[parent.0].children.contains(&child.0) {
+            return Ok(());
+        }
+        if self.reaches(child.0, parent.0) {
+            return Err(CycleError { parent, child });
+        }
+        self.nodes[parent.0].children.push(child.0);
+        self.nodes[child.0].parents.push(parent.0);
+        Ok(())
+    }
+
+    /// Returns the number of nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns whether the graph has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns whether there is a path from node `from` to node `to`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = vec![from];
+        while let Some(node) = stack.pop() {
+            if node == to {
+                return true;
+            }
+            if !visited[node] {
+                visited[node] = true;
+                stack.extend(&self.nodes[node].children);
+            }
+        }
+        false
+    }
+}
+
+impl<T, E> Default for JobGraph<T, E> {
+    fn default() -> JobGraph<T, E> {
+        JobGraph::new()
+    }
+}
+
+/// The error returned by `JobGraph::add_edge` when the edge would create a
+/// cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CycleError {
+    pub parent: NodeId,
+    pub child: NodeId,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "edge from node {} to node {} would create a cycle",
+            self.parent.0, self.child.0
+        )
+    }
+}
+
+impl Error for CycleError {}
+
+/// The reason a `JobGraph` did not complete. Nodes that had not started
+/// when a node failed are skipped.
+#[derive(Debug)]
+pub enum GraphError<E> {
+    /// The job of a node returned an error.
+    Failed { node: NodeId, error: E },
+    /// The job of a node panicked. Contains the panic payload.
+    Panicked {
+        node: NodeId,
+        payload: Box<dyn Any + Send + 'static>,
+    },
+}
+
+impl<E> GraphError<E> {
+    /// Returns the node that failed.
+    pub fn node(&self) -> NodeId {
+        match self {
+            GraphError::Failed { node, .. } | GraphError::Panicked { node, .. } => *node,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for GraphError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::Failed { node, error } => write!(f, "node {} failed: {}", node.0, error),
+            GraphError::Panicked { node, payload } => {
+                write!(f, "node {} panicked: {}", node.0, panic_message(&**payload))
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for GraphError<E> {}
+
+/// The results of a `JobGraph` that completed, indexed by `NodeId`.
+#[derive(Debug)]
+pub struct GraphResults<T> {
+    results: Vec<T>,
+}
+
+impl<T> GraphResults<T> {
+    /// Returns the results in the order the nodes were added.
+    pub fn into_vec(self) -> Vec<T> {
+        self.results
+    }
+}
+
+impl<T> Index<NodeId> for GraphResults<T> {
+    type Output = T;
+
+    fn index(&self, node: NodeId) -> &T {
+        &self.results[node.0]
+    }
+}
+
+/// A `JobGraph` while it runs.
+struct GraphRun<T, E> {
+    jobs: Vec<Mutex<Option<NodeJob<T, E>>>>,
+    parents: Vec<Vec<usize>>,
+    children: Vec<Vec<usize>>,
+    /// The number of parents of each node that have not completed yet.
+    waiting_for: Vec<AtomicUsize>,
+    results: Vec<OnceLock<T>>,
+    failed: AtomicBool,
+    /// The first node that failed.
+    error: Mutex<Option<GraphError<E>>>,
+}
+
+impl<T, E> GraphRun<T, E>
+where
+    T: Send + Sync,
+    E: Send,
+{
+    fn spawn<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>, node: usize) {
+        scope.spawn(move || self.run(scope, node));
+    }
+
+    fn run<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>, node: usize) {
+        if self.failed.load(Ordering::Acquire) {
+            worker::report(Outcome::Cancelled);
+            return;
+        }
+        let job = self.jobs[node].lock().unwrap().take().unwrap();
+        let inputs: Vec<&T> = self.parents[node]
+            .iter()
+            .map(|&parent| self.results[parent].get().unwrap())
+            .collect();
+
+        let node_id = NodeId(node);
+        match panic::catch_unwind(AssertUnwindSafe(|| job(&inputs))) {
+            Ok(Ok(value)) => {
+                // Each node runs once, so its result is not set yet.
+                let _ = self.results[node].set(value);
+                for &child in &self.children[node] {
+                    if self.waiting_for[child].fetch_sub(1, Ordering::AcqRel) == 1 {
+                        self.spawn(scope, child);
+                    }
+                }
+            }
+            Ok(Err(error)) => self.fail(GraphError::Failed {
+                node: node_id,
+                error,
+            }),
+            Err(payload) => {
+                worker::report(Outcome::Panicked);
+                self.fail(GraphError::Panicked {
+                    node: node_id,
+                    payload,
+                });
+            }
+        }
+    }
+
+    fn fail(&self, error: GraphError<E>) {
+        self.failed.store(true, Ordering::Release);
+        self.error.lock().unwrap().get_or_insert(error);
+    }
+}
+
+impl ThreadPool {
+    /// Runs the jobs of `graph` on the pool and blocks until they have
+    /// completed. Each job is queued as soon as all of its parents have
+    /// completed, so independent jobs run in parallel.
+    ///
+    /// Returns the results of all jobs, or the first node that failed. Once a
+    /// node has failed, no more jobs are started, and the jobs that are
+    /// already running are waited for.
+    ///
+    /// When called from one of this pool's workers, the worker runs queued
+    /// jobs while it waits, as in `scope`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the pool is shutting down, or if jobs of the graph are
+    /// dropped without running, for example by `shutdown_now`.
+    pub fn run_graph<T, E>(&self, graph: JobGraph<T, E>) -> Result<GraphResults<T>, GraphError<E>>
+    where
+        T: Send + Sync,
+        E: Send,
+    {
+        let count = graph.nodes.len();
+        let mut run = GraphRun {
+            jobs: Vec::with_capacity(count),
+            parents: Vec::with_capacity(count),
+            children: Vec::with_capacity(count),
+            waiting_for: Vec::with_capacity(count),
+            results: (0..count).map(|_| OnceLock::new()).collect(),
+            failed: AtomicBool::new(false),
+            error: Mutex::new(None),
+        };
+        for node in graph.nodes {
+            run.jobs.push(Mutex::new(Some(node.job)));
+            run.waiting_for.push(AtomicUsize::new(node.parents.len()));
+            run.parents.push(node.parents);
+            run.children.push(node.children);
+        }
+
+        self.scope(|scope| {
+            for (node, parents) in run.parents.iter().enumerate() {
+                if parents.is_empty() {
+                    run.spawn(scope, node);
+                }
+            }
+        });
+
+        if let Some(error) = run.error.into_inner().unwrap() {
+            return Err(error);
+        }
+        // Without a failure, every node has completed.
+        let results = run
+            .results
+            .into_iter()
+            .map(|result| result.into_inner().unwrap())
+            .collect();
+        Ok(GraphResults { results })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+
+    use super::{CycleError, GraphError, JobGraph};
+    use crate::ThreadPool;
+
+    #[test]
+    fn results_flow_to_the_children() {
+        let pool = ThreadPool::new(3);
+        let mut graph: JobGraph<u64, ()> = JobGraph::new();
+        let a = graph.add_node(|_| Ok(2));
+        let b = graph.add_node(|parents| Ok(*parents[0] * 10));
+        let c = graph.add_node(|parents| Ok(*parents[0] + 1));
+        let d = graph.add_node(|parents| Ok(*parents[0] * 1000 + *parents[1]));
+        graph.add_edge(a, b).unwrap();
+        graph.add_edge(a, c).unwrap();
+        graph.add_edge(b, d).unwrap();
+        graph.add_edge(c, d).unwrap();
+        graph.add_edge(c, d).unwrap();
+        assert_eq!(
+            graph.add_edge(d, a),
+            Err(CycleError {
+                parent: d,
+                child: a
+            })
+        );
+        assert!(graph.add_edge(a, a).is_err());
+
+        let results = pool.run_graph(graph).unwrap();
+        assert_eq!(results[d], 20003);
+        assert_eq!(results.into_vec(), [2, 20, 3, 20003]);
+
+        let empty: JobGraph<(), ()> = JobGraph::new();
+        assert!(pool.run_graph(empty).unwrap().into_vec().is_empty());
+    }
+
+    #[test]
+    fn independent_nodes_run_in_parallel() {
+        let pool = ThreadPool::new(4);
+        let barrier = Arc::new(Barrier::new(4));
+        let mut graph: JobGraph<(), ()> = JobGraph::new();
+        let join = graph.add_node(|parents| {
+            assert_eq!(parents.len(), 4);
+            Ok(())
+        });
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            let leaf = graph.add_node(move |_| {
+                barrier.wait();
+                Ok(())
+            });
+            graph.add_edge(leaf, join).unwrap();
+        }
+        pool.run_graph(graph).unwrap();
+    }
+
+    #[test]
+    fn failure_skips_the_descendants() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let mut graph: JobGraph<(), &str> = JobGraph::new();
+        let a = graph.add_node(|_| Ok(()));
+        let failing = graph.add_node(|_| Err("failed"));
+        let after = graph.add_node({
+            let ran = Arc::clone(&ran);
+            move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+        graph.add_edge(a, failing).unwrap();
+        graph.add_edge(failing, after).unwrap();
+        let err = pool.run_graph(graph).unwrap_err();
+        assert_eq!(err.node(), failing);
+        assert!(matches!(
+            err,
+            GraphError::Failed {
+                error: "failed",
+                ..
+            }
+        ));
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+        let mut graph: JobGraph<(), &str> = JobGraph::new();
+        let panicking = graph.add_node(|_| panic!("node panicked"));
+        let err = pool.run_graph(graph).unwrap_err();
+        assert_eq!(err.node(), panicking);
+        assert_eq!(err.to_string(), "node 0 panicked: node panicked");
+    }
+
+    #[test]
+    fn run_graph_on_a_worker() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let handle = pool.submit({
+            let pool = Arc::clone(&pool);
+            move || {
+                let mut graph: JobGraph<usize, ()> = JobGraph::new();
+                let mut last = graph.add_node(|_| Ok(0));
+                for _ in 0..50 {
+                    let next = graph.add_node(|parents| Ok(*parents[0] + 1));
+                    graph.add_edge(last, next).unwrap();
+                    last = next;
+                }
+                pool.run_graph(graph).unwrap()[last]
+            }
+        });
+        assert_eq!(handle.join().unwrap(), 50);
+    }
+}
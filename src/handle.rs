@@ -0,0 +1,430 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Job, Shared};
+
+/// The reason a job did not produce a result.
+#[derive(Debug)]
+pub enum JobError {
+    /// The job was dropped before it produced a result, or its result was
+    /// already taken from the handle.
+    Dropped,
+    /// The job was cancelled before it started, or, for a future, before it
+    /// completed.
+    Cancelled,
+    /// The job panicked. Contains the panic payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job ran longer than its timeout, see
+    /// `ThreadPool::submit_with_timeout`. It may still be running.
+    TimedOut,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Dropped => write!(f, "job was dropped before producing a result"),
+            JobError::Cancelled => write!(f, "job was cancelled"),
+            JobError::Panicked(payload) => write!(f, "job panicked: {}", panic_message(&**payload)),
+            JobError::TimedOut => write!(f, "job timed out"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// Returns the message of a panic payload, if it has one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// A flag that tells a running job to stop early. Jobs have to check it
+/// themselves, see `ThreadPool::submit_cancellable`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Sets the flag. It cannot be cleared again.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether both tokens are clones of the same token.
+    pub(crate) fn ptr_eq(&self, other: &CancellationToken) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+/// Where a job submitted with `ThreadPool::submit` is at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The job is waiting in the queue.
+    Queued,
+    /// The job is running.
+    Running,
+    /// The job returned.
+    Completed,
+    /// The job panicked.
+    Panicked,
+    /// The job was cancelled before it started, or, for a future, before it
+    /// completed.
+    Cancelled,
+    /// The job was dropped without running, for example by
+    /// `ThreadPool::shutdown_now`.
+    Dropped,
+    /// The job ran longer than its timeout. It may still be running, but its
+    /// result is discarded.
+    TimedOut,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+/// The state shared between a `JobHandle` and its job.
+struct JobState<T> {
+    slot: Mutex<Slot<T>>,
+    finished: Condvar,
+    token: CancellationToken,
+    /// The pool the job is queued on, which `JobHandle::cancel` takes the job
+    /// out of.
+    pool: Weak<Shared>,
+}
+
+struct Slot<T> {
+    status: JobStatus,
+    /// Set once the job has finished, until it is taken by the handle.
+    result: Option<Result<T, JobError>>,
+    /// The task that awaits the handle, see `JobHandle::poll_join`.
+    waker: Option<Waker>,
+}
+
+impl<T> JobState<T> {
+    /// Finishes the job with `status` and `result`, unless it has finished
+    /// already. The task awaiting the handle is woken after `slot` is
+    /// unlocked, since waking it may poll the handle right away.
+    fn finish(
+        &self,
+        mut slot: MutexGuard<'_, Slot<T>>,
+        status: JobStatus,
+        result: Result<T, JobError>,
+    ) {
+        if slot.status.is_finished() {
+            return;
+        }
+        slot.status = status;
+        slot.result = Some(result);
+        self.finished.notify_all();
+        let waker = slot.waker.take();
+        drop(slot);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The job's end of a `JobHandle`. Dropping it before the job has finished
+/// marks the job as dropped.
+pub(crate) struct Completer<T> {
+    state: Arc<JobState<T>>,
+}
+
+impl<T> Completer<T> {
+    /// Marks the job as running and returns its cancellation token, or
+    /// returns `None` if the job was cancelled and must not run.
+    pub(crate) fn start(&self) -> Option<CancellationToken> {
+        let mut slot = self.state.slot.lock().unwrap();
+        if slot.status != JobStatus::Queued {
+            return None;
+        }
+        slot.status = JobStatus::Running;
+        Some(self.state.token.clone())
+    }
+
+    /// Like `start`, for a job that runs in several steps, such as a future
+    /// that is polled more than once. Returns `false` if the job was
+    /// cancelled and must not continue.
+    pub(crate) fn resume(&self) -> bool {
+        let mut slot = self.state.slot.lock().unwrap();
+        match slot.status {
+            JobStatus::Queued => {
+                slot.status = JobStatus::Running;
+                true
+            }
+            JobStatus::Running => true,
+            _ => false,
+        }
+    }
+
+    /// Stores the result of the job.
+    pub(crate) fn complete(self, result: thread::Result<T>) {
+        let (status, result) = match result {
+            Ok(value) => (JobStatus::Completed, Ok(value)),
+            Err(payload) => (JobStatus::Panicked, Err(JobError::Panicked(payload))),
+        };
+        let slot = self.state.slot.lock().unwrap();
+        self.state.finish(slot, status, result);
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        let slot = self.state.slot.lock().unwrap();
+        self.state
+            .finish(slot, JobStatus::Dropped, Err(JobError::Dropped));
+    }
+}
+
+/// A handle to the result of a job submitted with `ThreadPool::submit`.
+///
+/// Dropping the handle does not cancel the job.
+pub struct JobHandle<T> {
+    state: Arc<JobState<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Creates a handle for a job queued on `pool`. The job's task has to
+    /// carry the handle's token, see `Task::token`.
+    pub(crate) fn new(pool: Weak<Shared>) -> (Completer<T>, JobHandle<T>) {
+        let state = Arc::new(JobState {
+            slot: Mutex::new(Slot {
+                status: JobStatus::Queued,
+                result: None,
+                waker: None,
+            }),
+            finished: Condvar::new(),
+            token: CancellationToken::new(),
+            pool,
+        });
+        let completer = Completer {
+            state: Arc::clone(&state),
+        };
+        (completer, JobHandle { state })
+    }
+
+    /// Cancels the job. If it has not started yet, it is taken out of the
+    /// queue, so it no longer counts towards the queue's capacity, and the
+    /// handle returns `JobError::Cancelled`. A job that a tenant's limit
+    /// holds back is skipped once it is admitted instead.
+    ///
+    /// If the job is already running, its `CancellationToken` is cancelled.
+    /// The job decides itself whether to stop early, and its result is
+    /// returned as usual.
+    ///
+    /// Returns `true` if the job was cancelled before it started.
+    pub fn cancel(&self) -> bool {
+        self.state.token.cancel();
+        let slot = self.state.slot.lock().unwrap();
+        if slot.status != JobStatus::Queued {
+            return false;
+        }
+        self.state
+            .finish(slot, JobStatus::Cancelled, Err(JobError::Cancelled));
+        if let Some(pool) = self.state.pool.upgrade() {
+            pool.remove_cancelled(&self.state.token);
+        }
+        true
+    }
+
+    /// Returns the token that is passed to the job.
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.state.token.clone()
+    }
+
+    /// Returns where the job is at, without blocking.
+    pub fn status(&self) -> JobStatus {
+        self.state.slot.lock().unwrap().status
+    }
+
+    /// Blocks until the job has finished and returns its result.
+    pub fn join(self) -> Result<T, JobError> {
+        let slot = self.state.slot.lock().unwrap();
+        let slot = self
+            .state
+            .finished
+            .wait_while(slot, |slot| !slot.status.is_finished())
+            .unwrap();
+        take_result(slot)
+    }
+
+    /// Returns the result of the job if it has finished, without blocking.
+    ///
+    /// Returns `None` if the job is still queued or running.
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        let slot = self.state.slot.lock().unwrap();
+        if !slot.status.is_finished() {
+            return None;
+        }
+        Some(take_result(slot))
+    }
+
+    /// Blocks until the job has finished or `timeout` has elapsed.
+    ///
+    /// Returns `None` if the job did not finish in time.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        let deadline = Instant::now() + timeout;
+        let mut slot = self.state.slot.lock().unwrap();
+        while !slot.status.is_finished() {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            slot = self
+                .state
+                .finished
+                .wait_timeout(slot, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Some(take_result(slot))
+    }
+
+    /// Cancels the job even if it is running, for jobs that check in between
+    /// steps, see `Completer::resume`. Returns `false` if the job had already
+    /// finished.
+    pub(crate) fn abort(&self) -> bool {
+        self.state.token.cancel();
+        let slot = self.state.slot.lock().unwrap();
+        if slot.status.is_finished() {
+            return false;
+        }
+        self.state
+            .finish(slot, JobStatus::Cancelled, Err(JobError::Cancelled));
+        true
+    }
+
+    /// Returns a function that marks the job as timed out and cancels its
+    /// token, unless it has finished already. Called by the watchdog once
+    /// the job has run past its deadline.
+    pub(crate) fn expiry(&self) -> Job
+    where
+        T: Send + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        Box::new(move || {
+            state.token.cancel();
+            let slot = state.slot.lock().unwrap();
+            state.finish(slot, JobStatus::TimedOut, Err(JobError::TimedOut));
+        })
+    }
+
+    /// Returns the result of the job if it has finished, or else registers
+    /// the task of `cx` to be woken up when it does.
+    pub(crate) fn poll_join(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, JobError>> {
+        let mut slot = self.state.slot.lock().unwrap();
+        if slot.status.is_finished() {
+            return Poll::Ready(take_result(slot));
+        }
+        if !slot
+            .waker
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            slot.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+fn take_result<T>(mut slot: MutexGuard<'_, Slot<T>>) -> Result<T, JobError> {
+    slot.result.take().unwrap_or(Err(JobError::Dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{JobError, JobStatus};
+    use crate::tests::occupy;
+    use crate::{OverflowPolicy, ThreadPool, ThreadPoolBuilder};
+
+    #[test]
+    fn cancel_queued_job() {
+        let pool = ThreadPool::new(1);
+        let release = occupy(&pool);
+        let handle = pool.submit(|| panic!("cancelled job ran"));
+        assert_eq!(handle.status(), JobStatus::Queued);
+        assert!(handle.cancel());
+        assert_eq!(handle.status(), JobStatus::Cancelled);
+        assert_eq!(pool.stats().queued, 0);
+        drop(release);
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert_eq!(stats.cancelled, 1);
+        assert_eq!(stats.panicked, 0);
+    }
+
+    #[test]
+    fn cancel_frees_queue_capacity() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+        let handle = pool.submit(|| ());
+        assert!(pool.try_execute(|| ()).is_err());
+        assert!(handle.cancel());
+        assert!(pool.try_execute(|| ()).is_ok());
+        drop(release);
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn cancel_running_job() {
+        let pool = ThreadPool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let handle = pool.submit_cancellable(move |token| {
+            started_tx.send(()).unwrap();
+            while !token.is_cancelled() {
+                std::thread::yield_now();
+            }
+            7
+        });
+        started_rx.recv().unwrap();
+        assert!(!handle.cancel());
+        assert_eq!(handle.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn panic_reaches_the_handle() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.set_panic_handler(move |_, _| tx.send(()).unwrap());
+        let handle = pool.submit(|| -> u32 { panic!("job {}", 7) });
+        match handle.join() {
+            Err(err @ JobError::Panicked(_)) => assert_eq!(err.to_string(), "job panicked: job 7"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert_eq!(pool.submit(|| 5).join().unwrap(), 5);
+        assert_eq!(pool.panic_count(), 1);
+        // Panics of submitted jobs do not reach the panic handler.
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::metrics::Outcome;
+use crate::scheduler::{Overflow, Task};
+use crate::{worker, CancellationToken, Job, ThreadPool};
+
+/// Where the job of `ThreadPool::join` was queued.
+enum Queued {
+    /// In the local queue of the calling worker, at the given address.
+    Local(*const ()),
+    Injected,
+    No,
+}
+
+/// The closure of `ThreadPool::join` that other workers may take.
+struct JoinState<B, R> {
+    /// The closure, until it is claimed by the queued job or by the caller.
+    job: Mutex<Option<B>>,
+    /// The result of the closure if the queued job claimed it.
+    result: Mutex<Option<thread::Result<R>>>,
+    done: Condvar,
+}
+
+impl ThreadPool {
+    /// Runs `a` and `b`, possibly in parallel, and returns both results.
+    ///
+    /// `a` runs on the calling thread, while `b` is queued for idle workers.
+    /// If no worker has taken `b` by the time `a` returns, the caller runs it
+    /// as well. Otherwise it waits for `b`, and when called from one of this
+    /// pool's workers, runs queued jobs in the meantime. If the queue is full,
+    /// `b` is not queued and runs on the calling thread after `a`. This makes
+    /// `join` suited for recursive divide-and-conquer algorithms, which call
+    /// it from the jobs it runs.
+    ///
+    /// Both closures may borrow from the caller's stack.
+    ///
+    /// # Panics
+    ///
+    /// If `a` or `b` panics, the panic is resumed here, the one of `a` first.
+    /// `b` is not run after `a` panicked, unless a worker has taken it
+    /// already, and then it is waited for before the panic is resumed.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let state = Arc::new(JoinState {
+            job: Mutex::new(Some(b)),
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+
+        let job_state = Arc::clone(&state);
+        let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            // The caller only claims `b` once the job can no longer run, so
+            // the job always finds it.
+            if let Some(b) = job_state.job.lock().unwrap().take() {
+                let result = panic::catch_unwind(AssertUnwindSafe(b));
+                if result.is_err() {
+                    worker::report(Outcome::Panicked);
+                }
+                *job_state.result.lock().unwrap() = Some(result);
+                job_state.done.notify_all();
+            }
+        });
+        // SAFETY: `join` does not return before either the job has been
+        // dropped without running, or `b` has been run by the job and its
+        // result stored. In both cases the job does not touch anything
+        // borrowed by `b` or its result after `join` returns.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + '_>, Job>(job) };
+        // Identifies the job in the injector, see `Scheduler::remove`.
+        let token = CancellationToken::new();
+        let task = Task {
+            scoped: true,
+            token: Some(token.clone()),
+            ..Task::new(job)
+        };
+        // On a worker, the job goes into the worker's own queue, and else
+        // into the injector. After `a`, the caller takes the job back unless
+        // a worker has taken it already. If the job cannot be queued, `b` is
+        // left for the caller.
+        let scheduler = &self.shared.scheduler;
+        let local = scheduler.current_local();
+        let queued = match &local {
+            Some(local) => match scheduler.push_join(local, task.boxed()) {
+                Some(job) => {
+                    self.shared.grow();
+                    Queued::Local(job)
+                }
+                None => Queued::No,
+            },
+            None => match self.shared.push(task, Overflow::Reject) {
+                Ok(()) => Queued::Injected,
+                Err(_) => Queued::No,
+            },
+        };
+
+        let result_a = panic::catch_unwind(AssertUnwindSafe(a));
+
+        let taken_back = match (&local, queued) {
+            (Some(local), Queued::Local(job)) => scheduler.take_back(local, job),
+            (_, Queued::Injected) => scheduler.remove(&token).is_some(),
+            _ => true,
+        };
+        // A job that a worker has taken is left to run `b`.
+        let b = if taken_back {
+            state.job.lock().unwrap().take()
+        } else {
+            None
+        };
+        let result_b = match b {
+            Some(b) if result_a.is_ok() => Some(panic::catch_unwind(AssertUnwindSafe(b))),
+            Some(_) => None,
+            None => Some(self.wait_for_join(&state)),
+        };
+
+        let result_a = match result_a {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        match result_b {
+            Some(Ok(value)) => (result_a, value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => unreachable!("`b` is only skipped when `a` panicked"),
+        }
+    }
+
+    /// Blocks until the queued job of `join` has run `b`.
+    fn wait_for_join<B, R>(&self, state: &JoinState<B, R>) -> thread::Result<R> {
+        let mut result = state.result.lock().unwrap();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            drop(result);
+            let helped = worker::help(&self.shared);
+            result = state.result.lock().unwrap();
+            if !helped && result.is_none() {
+                result = state.done.wait(result).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    use crate::handle::panic_message;
+    use crate::tests::occupy;
+    use crate::{OverflowPolicy, ThreadPool, ThreadPoolBuilder};
+
+    fn sum(pool: &ThreadPool, values: &[u64]) -> u64 {
+        if values.len() <= 8 {
+            return values.iter().sum();
+        }
+        let (left, right) = values.split_at(values.len() / 2);
+        let (a, b) = pool.join(|| sum(pool, left), || sum(pool, right));
+        a + b
+    }
+
+    #[test]
+    fn join_outside_the_pool() {
+        let pool = ThreadPool::new(2);
+        let values: Vec<u64> = (1..=1000).collect();
+        assert_eq!(sum(&pool, &values), 500_500);
+        let (a, b) = pool.join(|| values.len(), || values[0]);
+        assert_eq!((a, b), (1000, 1));
+    }
+
+    #[test]
+    fn join_inside_the_pool() {
+        for threads in [1, 4] {
+            let pool = Arc::new(ThreadPool::new(threads));
+            let values: Vec<u64> = (1..=1000).collect();
+            let handle = pool.submit({
+                let pool = Arc::clone(&pool);
+                move || sum(&pool, &values)
+            });
+            assert_eq!(handle.join().unwrap(), 500_500);
+            pool.wait_idle();
+            assert_eq!(pool.stats().queued, 0);
+        }
+    }
+
+    #[test]
+    fn panic_in_b_is_resumed() {
+        let pool = ThreadPool::new(2);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.join(|| 1, || -> i32 { panic!("b panicked") })
+        }));
+        assert_eq!(panic_message(&*result.unwrap_err()), "b panicked");
+    }
+
+    #[test]
+    fn join_outside_the_pool_leaves_nothing_queued() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+        // The only worker is busy, so the caller runs `b` itself.
+        assert_eq!(pool.join(|| 1, || 2), (1, 2));
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.cancelled), (0, 0));
+        assert!(pool.try_execute(|| ()).is_ok());
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(pool.stats().cancelled, 0);
+    }
+}
@@ -1,121 +1,933 @@
-use std::sync::mpsc;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
+use std::any::Any;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use log::{info, debug};
+use log::{debug, error, info};
 
-enum WorkerMessage {
-    NewJob(Job),
-    Shutdown,
-}
+mod builder;
+mod deadline;
+mod error;
+mod executor;
+mod graph;
+mod handle;
+mod join;
+mod metrics;
+#[cfg(feature = "openmetrics")]
+mod openmetrics;
+mod parallel;
+mod scaling;
+mod scheduler;
+mod scope;
+mod tenant;
+mod timer;
+mod trace;
+mod watchdog;
+mod worker;
+
+pub use builder::{OverflowPolicy, ThreadPoolBuilder};
+pub use error::{ThreadPoolError, TryExecuteError};
+pub use executor::JoinHandle;
+pub use graph::{CycleError, GraphError, GraphResults, JobGraph, NodeId};
+pub use handle::{CancellationToken, JobError, JobHandle, JobStatus};
+pub use metrics::{Histogram, PoolStats, TenantStats, WorkerStats};
+#[cfg(feature = "openmetrics")]
+pub use openmetrics::render_openmetrics;
+pub use parallel::Parallel;
+pub use scheduler::Priority;
+pub use scope::Scope;
+pub use timer::{MissedTicks, Schedule, TimerHandle};
+
+use metrics::{Metrics, Outcome};
+use scaling::Scaling;
+use scheduler::{Overflow, Scheduler, Task};
+use tenant::Tenants;
+use timer::Timer;
+use watchdog::Watchdog;
+use worker::{ThreadConfig, Worker, Workers};
+
+/// A job queued on a `ThreadPool`.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
 
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+type PanicHandler = Box<dyn Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static>;
+
+type TimeoutHandler = Box<dyn Fn(usize, Duration) + Send + Sync + 'static>;
+
+/// State shared between the pool and its workers.
+struct Shared {
+    scheduler: Scheduler,
+    timer: Timer,
+    watchdog: Watchdog,
+    workers: Mutex<Workers>,
+    /// Set if the pool scales its number of workers by itself.
+    scaling: Option<Scaling>,
+    thread_config: ThreadConfig,
+    metrics: Metrics,
+    tenants: Tenants,
+    panic_handler: RwLock<Option<PanicHandler>>,
+    timeout_handler: RwLock<Option<TimeoutHandler>>,
+    /// Whether workers stuck on a job past its timeout are replaced.
+    replace_stuck_workers: bool,
 }
 
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<WorkerMessage>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            match message {
-                WorkerMessage::NewJob(job) => {
-                    job();
-                }
-                WorkerMessage::Shutdown => {
-                    debug!(
-                        "Worker {} received shutdown message, terminating thread.",
-                        id
+impl Shared {
+    /// Queues a job, and starts another worker if the pool autoscales and
+    /// the queue backs up.
+    fn push<F>(
+        self: &Arc<Shared>,
+        task: Task<F>,
+        overflow: Overflow,
+    ) -> Result<(), TryExecuteError<Task<F>>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.scheduler.push(task, overflow)?;
+        self.grow();
+        Ok(())
+    }
+
+    /// Takes the cancelled job with `token` out of the queues, if no worker
+    /// has taken it yet, and counts it as cancelled.
+    fn remove_cancelled(&self, token: &CancellationToken) {
+        if let Some(task) = self.scheduler.remove(token) {
+            self.metrics.record(Outcome::Cancelled);
+            self.tenants.record_cancelled(task.queue);
+            // The job has finished already, so dropping it does not mark it
+            // as dropped.
+            drop(task);
+        }
+    }
+
+    /// Called by worker `id` when a job that has no result handle panicked.
+    /// A panic in the handler itself is logged, so it cannot take down the
+    /// worker.
+    fn handle_panic(&self, id: usize, payload: Box<dyn Any + Send>) {
+        match &*self.panic_handler.read().unwrap() {
+            Some(handler) => {
+                if let Err(err) = panic::catch_unwind(AssertUnwindSafe(|| handler(id, payload))) {
+                    error!(
+                        "Panic handler panicked on worker {}: {}",
+                        id,
+                        handle::panic_message(&*err)
                     );
-                    break;
                 }
             }
-        });
-        Worker {
-            id,
-            thread: Some(thread),
+            None => error!(
+                "Job on worker {} panicked: {}",
+                id,
+                handle::panic_message(&*payload)
+            ),
         }
     }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// Wraps `f` into a job that stores its result in the returned handle.
+fn with_handle<F, T>(
+    shared: &Arc<Shared>,
+    f: F,
+) -> (Task<impl FnOnce() + Send + 'static>, JobHandle<T>)
+where
+    F: FnOnce(&CancellationToken) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (completer, handle) = JobHandle::new(Arc::downgrade(shared));
+    let job = move || {
+        let token = match completer.start() {
+            Some(token) => token,
+            None => {
+                worker::report(Outcome::Cancelled);
+                return;
+            }
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&token)));
+        if result.is_err() {
+            worker::report(Outcome::Panicked);
+        }
+        completer.complete(result);
+    };
+    let task = Task {
+        token: Some(handle.token()),
+        ..Task::new(job)
+    };
+    (task, handle)
+}
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<WorkerMessage>,
-    receiver: Arc<Mutex<mpsc::Receiver<WorkerMessage>>>,
+    overflow_policy: OverflowPolicy,
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
-    /// Creates a ThreadPool with `thread_count` threads.
+    /// Creates a ThreadPool with `thread_count` threads and an unbounded
+    /// queue. Use `ThreadPoolBuilder` for more options.
     ///
     /// # Panics
     ///
-    /// This will panic if the thread count is zero.
+    /// This will panic if the thread count is zero, or if a thread cannot be
+    /// spawned. See `try_new` for a variant that returns an error instead.
     pub fn new(thread_count: usize) -> ThreadPool {
-        assert_ne!(thread_count, 0);
+        match ThreadPool::try_new(thread_count) {
+            Ok(pool) => pool,
+            Err(err) => panic!("failed to create ThreadPool: {}", err),
+        }
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Creates a ThreadPool with `thread_count` threads and an unbounded
+    /// queue, or returns an error if the thread count is zero or a thread
+    /// cannot be spawned.
+    pub fn try_new(thread_count: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPoolBuilder::new().thread_count(thread_count).build()
+    }
 
-        let mut workers = Vec::with_capacity(thread_count);
+    /// Returns the number of threads in the pool, not counting threads that
+    /// are retiring.
+    pub fn thread_count(&self) -> usize {
+        self.shared.workers.lock().unwrap().active.len()
+    }
 
-        // Create the threads:
-        for i in 0..thread_count {
-            workers.push(Worker::new(i + 1, Arc::clone(&receiver)));
-        }
+    /// Changes the number of threads in the pool to `new_thread_count`.
+    ///
+    /// When shrinking, this blocks until the retired workers have finished
+    /// their current job and their threads have been joined. See `resize` for
+    /// a non-blocking variant.
+    pub fn set_thread_count(&mut self, new_thread_count: usize) -> Result<(), ThreadPoolError> {
+        self.resize(new_thread_count)?.wait()
+    }
 
-        ThreadPool {
-            workers,
-            sender,
-            receiver,
+    /// Changes the number of threads in the pool to `new_thread_count`
+    /// without waiting for retiring workers.
+    ///
+    /// When shrinking, the most recently started workers are retired. Each of
+    /// them finishes its current job and stops, leaving its queued jobs to
+    /// the other workers. The returned handle can be used to wait for those
+    /// workers and join them.
+    ///
+    /// When growing, an error is returned if a thread cannot be spawned. The
+    /// threads spawned before the failure stay in the pool.
+    ///
+    /// Returns `ThreadPoolError::ShuttingDown` once the pool has been shut
+    /// down, and `ThreadPoolError::ZeroThreads` if `new_thread_count` is zero
+    /// and the pool does not autoscale, since it could never run a job again.
+    ///
+    /// An autoscaling pool keeps adding and retiring threads afterwards, see
+    /// `ThreadPoolBuilder::max_threads`.
+    pub fn resize(&mut self, new_thread_count: usize) -> Result<ResizeHandle, ThreadPoolError> {
+        if self.shared.scheduler.is_shut_down() {
+            return Err(ThreadPoolError::ShuttingDown);
         }
-    }
+        if new_thread_count == 0 && self.shared.scaling.is_none() {
+            return Err(ThreadPoolError::ZeroThreads);
+        }
+        let mut workers = self.shared.workers.lock().unwrap();
+        workers.reap();
+        let old_thread_count = workers.active.len();
+        let mut retiring = Vec::new();
 
-    pub fn set_thread_count(&mut self, new_thread_count: usize) {
-        let current_thread_count = self.workers.len();
-        if new_thread_count > current_thread_count {
-            for i in 0..(new_thread_count - current_thread_count) {
-                self.workers.push(Worker::new(
-                    i + 1 + current_thread_count,
-                    Arc::clone(&self.receiver),
-                ));
+        if new_thread_count > old_thread_count {
+            for _ in old_thread_count..new_thread_count {
+                workers.spawn(&self.shared)?;
             }
-        } else if new_thread_count < current_thread_count {
-            for _ in 0..(current_thread_count - new_thread_count) {
-                self.workers.pop();
+        } else {
+            retiring = workers.active.split_off(new_thread_count);
+            for worker in &retiring {
+                self.shared.scheduler.retire(&worker.local);
             }
         }
+
+        trace::resized(old_thread_count, new_thread_count);
+        Ok(ResizeHandle {
+            workers: retiring,
+            shared: Arc::downgrade(&self.shared),
+        })
+    }
+
+    /// Sets the handler that is called when a job submitted with `execute`
+    /// panics. The handler receives the id of the worker that ran the job and
+    /// the panic payload. Without a handler, the panic is logged.
+    ///
+    /// A job that ran on the submitting thread because of
+    /// `OverflowPolicy::CallerRuns` is reported with worker id 0.
+    ///
+    /// Panics of jobs submitted with `submit` are reported through their
+    /// `JobHandle` instead.
+    pub fn set_panic_handler<H>(&self, handler: H)
+    where
+        H: Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        *self.shared.panic_handler.write().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Returns the number of jobs that have panicked in this pool.
+    pub fn panic_count(&self) -> usize {
+        self.shared.metrics.panicked.load(Ordering::Relaxed) as usize
     }
 
     /// Execute something with one of the threads in the thread pool.
     ///
+    /// When called from a job that is running on this pool, `f` is queued on
+    /// the current worker, and other workers may steal it from there.
+    ///
+    /// A panic in `f` does not take down the worker thread. It is passed to
+    /// the panic handler, see `set_panic_handler`.
+    ///
+    /// If the queue is full, the pool's `OverflowPolicy` decides what happens
+    /// to `f`.
+    ///
     /// # Panics
     ///
-    /// This might panic if the sending of the job to the threads fails, but
-    /// that should never happen.
+    /// This will panic if the pool is shutting down, or if the queue is full
+    /// and the overflow policy is `OverflowPolicy::Reject`. See `try_execute`
+    /// for a variant that returns an error instead.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let message = WorkerMessage::NewJob(Box::new(f));
-        self.sender.send(message).unwrap();
+        self.execute_with_priority(Priority::Normal, f);
+    }
+
+    /// Like `execute`, but with a priority other than `Priority::Normal`.
+    /// Workers take the queued job with the highest priority first, see
+    /// `ThreadPoolBuilder::priority_aging` to keep low priority jobs from
+    /// starving.
+    ///
+    /// Unlike normal priority jobs, jobs with another priority are always
+    /// queued on the whole pool, even when submitted from a job running on
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_task(Task {
+            priority,
+            ..Task::new(f)
+        });
+    }
+
+    /// Like `execute`, but queues `f` in the named queue `queue`, see
+    /// `ThreadPoolBuilder::queue`.
+    ///
+    /// Unlike jobs in the default queue, jobs in named queues are always
+    /// queued on the whole pool, even when submitted from a job running on
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the pool has no queue named `queue`, and in the same
+    /// cases as `execute`.
+    pub fn execute_in<F>(&self, queue: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_task(Task {
+            queue: self.queue_index(queue),
+            ..Task::new(f)
+        });
+    }
+
+    /// Queues `task` like `execute` does.
+    fn execute_task<F>(&self, task: Task<F>)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(err) = self.queue(task, true) {
+            panic!("failed to execute job: {}", err);
+        }
+    }
+
+    /// Returns the index of the named queue `queue`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the pool has no queue named `queue`.
+    fn queue_index(&self, queue: &str) -> usize {
+        match self.shared.scheduler.queue_index(queue) {
+            Some(index) => index,
+            None => panic!("thread pool has no queue named {:?}", queue),
+        }
+    }
+
+    /// Like `execute`, but never blocks or panics, and never runs `f` on the
+    /// calling thread. If the queue is full and no job can be dropped to make
+    /// room, see `OverflowPolicy::DropOldest`, or if the pool is shutting
+    /// down, `f` is returned in an error.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue(Task::new(f), false)
+    }
+
+    /// Queues `task` according to the overflow policy. `block` says whether
+    /// `OverflowPolicy::Block` may block, and `OverflowPolicy::CallerRuns`
+    /// may run the job on the calling thread.
+    fn queue<F>(&self, task: Task<F>, block: bool) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let overflow = match self.overflow_policy {
+            OverflowPolicy::Block if block => Overflow::Block,
+            OverflowPolicy::Block | OverflowPolicy::Reject | OverflowPolicy::CallerRuns => {
+                Overflow::Reject
+            }
+            OverflowPolicy::DropOldest => Overflow::DropOldest { block },
+        };
+        match self.shared.push(task, overflow) {
+            Err(TryExecuteError::Full(task))
+                if block && self.overflow_policy == OverflowPolicy::CallerRuns =>
+            {
+                worker::run_on_caller(task, &self.shared);
+                Ok(())
+            }
+            result => result.map_err(|err| err.map(|task| task.job)),
+        }
+    }
+
+    /// Execute `f` with one of the threads in the thread pool and return a
+    /// handle to its return value. If `f` panics, the panic payload is
+    /// returned from the handle.
+    ///
+    /// The handle can cancel `f` as long as it has not started.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit_cancellable(move |_| f())
+    }
+
+    /// Like `submit`, but `f` receives a `CancellationToken` that is
+    /// cancelled when `JobHandle::cancel` is called while `f` is running.
+    /// Long running jobs can check it to stop early.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn submit_cancellable<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (task, handle) = with_handle(&self.shared, f);
+        self.execute_task(task);
+        handle
+    }
+
+    /// Like `submit`, but queues `f` in the named queue `queue`, see
+    /// `execute_in`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute_in`.
+    pub fn submit_in<F, T>(&self, queue: &str, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (task, handle) = with_handle(&self.shared, move |_| f());
+        self.execute_task(Task {
+            queue: self.queue_index(queue),
+            ..task
+        });
+        handle
+    }
+
+    /// Blocks until the queue is empty and no jobs are running. The pool can
+    /// be used as usual afterwards.
+    ///
+    /// Jobs queued while waiting are waited for as well. Delayed and periodic
+    /// jobs that are not due yet are not.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if called from a job running on this pool, which
+    /// would wait for itself.
+    pub fn wait_idle(&self) {
+        self.assert_not_on_worker("wait_idle");
+        self.shared.scheduler.wait_all_finished(None);
+    }
+
+    /// Like `wait_idle`, but waits at most `timeout`. Returns whether the
+    /// pool became idle in time.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `wait_idle`.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        self.assert_not_on_worker("wait_idle_timeout");
+        let deadline = Instant::now() + timeout;
+        self.shared.scheduler.wait_all_finished(Some(deadline))
+    }
+
+    fn assert_not_on_worker(&self, method: &str) {
+        assert!(
+            self.shared.scheduler.current_local().is_none(),
+            "ThreadPool::{} cannot be called from a job running on the same pool",
+            method
+        );
+    }
+
+    /// Shuts the pool down after all queued jobs have run, and blocks until
+    /// all workers have stopped.
+    ///
+    /// New jobs are rejected from now on. Jobs running on the pool can still
+    /// queue jobs, which are run as well. Dropping the pool does the same.
+    ///
+    /// Returns an error if a worker's thread panicked. The other workers are
+    /// still joined.
+    pub fn shutdown(&self) -> Result<(), ThreadPoolError> {
+        info!("Shutting down all ThreadPool workers.");
+        self.shared.scheduler.shutdown();
+        self.shared.timer.stop();
+
+        let mut result = Ok(());
+        for mut worker in self.take_workers() {
+            debug!("Waiting for worker {} to terminate.", worker.id);
+            if let Err(err) = worker.join() {
+                result = result.and(Err(err));
+            }
+        }
+        self.shared.watchdog.stop();
+        result
+    }
+
+    /// Shuts the pool down without running the queued jobs, and blocks until
+    /// all workers have finished their current job.
+    ///
+    /// Returns the jobs that were queued and had not started yet, including
+    /// jobs held back by a tenant's limit. Jobs spawned in a `scope` are not
+    /// returned but dropped, which makes the scope panic.
+    pub fn shutdown_now(&self) -> Vec<Job> {
+        info!("Shutting down all ThreadPool workers without running queued jobs.");
+        let (scoped, mut tasks): (Vec<Task>, Vec<Task>) = self
+            .shared
+            .scheduler
+            .shutdown_now()
+            .into_iter()
+            .partition(|task| task.scoped);
+        tasks.extend(self.shared.tenants.drain_pending());
+        self.shared.timer.stop();
+        // Scoped jobs borrow from the stack of a scope that waits for them,
+        // possibly on one of the workers we are about to join.
+        drop(scoped);
+
+        for mut worker in self.take_workers() {
+            debug!("Waiting for worker {} to terminate.", worker.id);
+            if let Err(err) = worker.join() {
+                error!("{}", err);
+            }
+        }
+        self.shared.watchdog.stop();
+        tasks.into_iter().map(|task| task.job).collect()
+    }
+
+    /// Like `shutdown`, but waits at most `timeout` for the workers to stop.
+    ///
+    /// Returns `ThreadPoolError::TimedOut` with the ids of the workers that
+    /// were still running when the time was up. Their threads are detached
+    /// and stop on their own once the queue is empty.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Result<(), ThreadPoolError> {
+        info!(
+            "Shutting down all ThreadPool workers, waiting at most {:?}.",
+            timeout
+        );
+        let deadline = Instant::now() + timeout;
+        self.shared.scheduler.shutdown();
+        self.shared.timer.stop();
+
+        let mut result = Ok(());
+        let mut unfinished = Vec::new();
+        for mut worker in self.take_workers() {
+            if !self.shared.scheduler.wait_stopped(&worker.local, deadline) {
+                unfinished.push(worker.id);
+            } else if let Err(err) = worker.join() {
+                result = result.and(Err(err));
+            }
+        }
+        self.shared.watchdog.stop();
+        if !unfinished.is_empty() {
+            return Err(ThreadPoolError::TimedOut {
+                workers: unfinished,
+            });
+        }
+        result
+    }
+
+    /// Takes the workers out of the pool to join them. When called from one
+    /// of the workers, that worker is left out, a thread cannot join itself.
+    fn take_workers(&self) -> Vec<Worker> {
+        let mut workers = {
+            let mut workers = self.shared.workers.lock().unwrap();
+            let mut taken = mem::take(&mut workers.active);
+            taken.append(&mut workers.retired);
+            taken
+        };
+        if let Some(current) = self.shared.scheduler.current_local() {
+            workers.retain(|worker| !Arc::ptr_eq(&worker.local, &current));
+        }
+        workers
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        info!("Shutting down all ThreadPool workers.");
+        if let Err(err) = self.shutdown() {
+            error!("{}", err);
+        }
+    }
+}
 
-        for _ in &self.workers {
-            self.sender.send(WorkerMessage::Shutdown).unwrap();
+/// A handle to the workers retired by `ThreadPool::resize`.
+///
+/// Dropping the handle does not stop the retirement. The pool then joins the
+/// retired threads itself, at the latest when it shuts down.
+pub struct ResizeHandle {
+    workers: Vec<Worker>,
+    shared: Weak<Shared>,
+}
+
+impl ResizeHandle {
+    /// Blocks until all retiring workers have stopped and their threads have
+    /// been joined.
+    ///
+    /// Returns an error if a retiring worker's thread panicked. The other
+    /// workers are still joined.
+    pub fn wait(mut self) -> Result<(), ThreadPoolError> {
+        let mut result = Ok(());
+        for mut worker in self.workers.drain(..) {
+            if let Err(err) = worker.join() {
+                result = result.and(Err(err));
+            }
         }
+        result
+    }
 
+    /// Joins the workers that have stopped so far, without blocking on the
+    /// others. Returns `true` once all retiring workers have been joined.
+    ///
+    /// Returns an error if a retiring worker's thread panicked.
+    pub fn try_wait(&mut self) -> Result<bool, ThreadPoolError> {
+        let mut result = Ok(());
         for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            if worker.is_finished() {
+                if let Err(err) = worker.join() {
+                    result = result.and(Err(err));
+                }
             }
         }
+        self.workers.retain(|worker| !worker.is_finished());
+        result.map(|()| self.workers.is_empty())
+    }
+}
+
+impl Drop for ResizeHandle {
+    fn drop(&mut self) {
+        // Once the pool is gone, there is nobody left to join the workers.
+        if let Some(shared) = self.shared.upgrade() {
+            shared
+                .workers
+                .lock()
+                .unwrap()
+                .retired
+                .append(&mut self.workers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::handle::panic_message;
+    use crate::{OverflowPolicy, ThreadPool, ThreadPoolBuilder, ThreadPoolError, TryExecuteError};
+
+    /// A pool with one worker and room for one queued job.
+    fn bounded(policy: OverflowPolicy) -> ThreadPool {
+        ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue_capacity(1)
+            .overflow_policy(policy)
+            .build()
+            .unwrap()
+    }
+
+    /// Keeps the only worker of `pool` busy until the returned sender is
+    /// dropped.
+    pub(crate) fn occupy(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        release_tx
+    }
+
+    #[test]
+    fn worker_survives_a_panicking_job() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.set_panic_handler(move |id, payload| {
+            tx.send((id, panic_message(&*payload).to_string())).unwrap();
+        });
+        pool.execute(|| panic!("job panicked"));
+        assert_eq!(rx.recv().unwrap(), (1, "job panicked".to_string()));
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(pool.panic_count(), 1);
+        assert_eq!(pool.thread_count(), 1);
+        pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn panicking_panic_handler_is_caught() {
+        let pool = ThreadPool::new(1);
+        pool.set_panic_handler(|_, _| panic!("handler panicked"));
+        pool.execute(|| panic!("job panicked"));
+        pool.wait_idle();
+        assert_eq!(pool.submit(|| 3).join().unwrap(), 3);
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn shrinking_lets_retiring_workers_finish_their_job() {
+        let mut pool = ThreadPool::new(2);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let started_tx = started_tx.clone();
+                let release_rx = Arc::clone(&release_rx);
+                pool.submit(move || {
+                    started_tx.send(()).unwrap();
+                    let _ = release_rx.lock().unwrap().recv();
+                    i
+                })
+            })
+            .collect();
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
+
+        let mut retiring = pool.resize(1).unwrap();
+        assert_eq!(pool.thread_count(), 1);
+        assert!(!retiring.try_wait().unwrap());
+        drop(release_tx);
+        retiring.wait().unwrap();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [0, 1]);
+
+        assert_eq!(pool.submit(|| 2).join().unwrap(), 2);
+        pool.set_thread_count(3).unwrap();
+        assert_eq!(pool.thread_count(), 3);
+    }
+
+    #[test]
+    fn resize_to_zero_threads_is_rejected() {
+        let mut pool = ThreadPool::new(2);
+        assert!(matches!(pool.resize(0), Err(ThreadPoolError::ZeroThreads)));
+        assert_eq!(pool.thread_count(), 2);
+        pool.shutdown().unwrap();
+        assert!(matches!(pool.resize(4), Err(ThreadPoolError::ShuttingDown)));
+    }
+
+    #[test]
+    fn reject_hands_the_job_back() {
+        let pool = bounded(OverflowPolicy::Reject);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let release = occupy(&pool);
+        let counter = Arc::clone(&ran);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(matches!(
+            pool.try_execute(|| ()),
+            Err(TryExecuteError::Full(_))
+        ));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.execute(|| ())));
+        assert!(result.is_err());
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let pool = bounded(OverflowPolicy::DropOldest);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let release = occupy(&pool);
+        pool.execute(|| panic!("dropped job ran"));
+        let counter = Arc::clone(&ran);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(pool.stats().queued, 1);
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.panic_count(), 0);
+    }
+
+    #[test]
+    fn caller_runs_when_full() {
+        let pool = bounded(OverflowPolicy::CallerRuns);
+        let release = occupy(&pool);
+        pool.execute(|| ());
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(thread::current().id()).unwrap());
+        assert_eq!(rx.try_recv().unwrap(), thread::current().id());
+        // `try_execute` never runs the job on the calling thread.
+        assert!(matches!(
+            pool.try_execute(|| ()),
+            Err(TryExecuteError::Full(_))
+        ));
+        drop(release);
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn caller_runs_catches_panics() {
+        let pool = bounded(OverflowPolicy::CallerRuns);
+        let (tx, rx) = mpsc::channel();
+        pool.set_panic_handler(move |id, _| tx.send(id).unwrap());
+        let release = occupy(&pool);
+        pool.execute(|| ());
+        pool.execute(|| panic!("job panicked on the caller"));
+        assert_eq!(rx.try_recv().unwrap(), 0);
+        assert_eq!(pool.panic_count(), 1);
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(pool.stats().completed, 2);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_scoped_jobs() {
+        let pool = bounded(OverflowPolicy::DropOldest);
+        let release = occupy(&pool);
+        let ran = AtomicBool::new(false);
+        pool.scope(|s| {
+            s.spawn(|| ran.store(true, Ordering::SeqCst));
+            // Only the scoped job is queued, so there is nothing to drop.
+            assert!(matches!(
+                pool.try_execute(|| ()),
+                Err(TryExecuteError::Full(_))
+            ));
+            thread::scope(|t| {
+                let submitter = t.spawn(|| pool.execute(|| ()));
+                drop(release);
+                submitter.join().unwrap();
+            });
+        });
+        assert!(ran.load(Ordering::SeqCst));
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn shutdown_runs_the_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.shutdown().unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+        assert_eq!(pool.thread_count(), 0);
+        assert!(matches!(
+            pool.try_execute(|| ()),
+            Err(TryExecuteError::ShuttingDown(_))
+        ));
+    }
+
+    #[test]
+    fn shutdown_now_returns_the_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        let release = occupy(&pool);
+        for _ in 0..10 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let jobs = thread::scope(|s| {
+            let shutdown = s.spawn(|| pool.shutdown_now());
+            // Release the worker once the queued jobs have been taken out.
+            while pool.stats().queued > 0 {
+                thread::yield_now();
+            }
+            drop(release);
+            shutdown.join().unwrap()
+        });
+        assert_eq!(jobs.len(), 10);
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        for job in jobs {
+            job();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn shutdown_timeout_reports_busy_workers() {
+        let pool = ThreadPool::new(1);
+        let release = occupy(&pool);
+        assert!(matches!(
+            pool.shutdown_timeout(Duration::from_millis(10)),
+            Err(ThreadPoolError::TimedOut { workers }) if workers == [1]
+        ));
+        drop(release);
+
+        let pool = ThreadPool::new(2);
+        pool.shutdown_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn wait_idle_waits_for_queued_and_running_jobs() {
+        let pool = ThreadPool::new(4);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..32 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.wait_idle();
+        assert_eq!(done.load(Ordering::SeqCst), 32);
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running), (0, 0));
+
+        let release = occupy(&pool);
+        assert!(!pool.wait_idle_timeout(Duration::from_millis(10)));
+        drop(release);
+        assert!(pool.wait_idle_timeout(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn wait_idle_on_a_worker_panics() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let handle = pool.submit({
+            let pool = Arc::clone(&pool);
+            move || pool.wait_idle()
+        });
+        assert!(matches!(handle.join(), Err(crate::JobError::Panicked(_))));
     }
 }
@@ -0,0 +1,388 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::ThreadPool;
+
+/// The number of histogram buckets. The bounds are 1µs, 2µs, 4µs and so on
+/// up to about 8 seconds, plus a last bucket without a bound.
+const BUCKETS: usize = 25;
+
+fn bucket_bound(index: usize) -> Duration {
+    if index + 1 == BUCKETS {
+        Duration::MAX
+    } else {
+        Duration::from_micros(1 << index)
+    }
+}
+
+/// A snapshot of the state of a `ThreadPool`, see `ThreadPool::stats`.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    /// The number of jobs waiting in the queues.
+    pub queued: usize,
+    /// The number of jobs that are running.
+    pub running: usize,
+    /// The number of workers that are not running a job.
+    pub idle_workers: usize,
+    /// The number of jobs that returned, since the pool was created.
+    pub completed: u64,
+    /// The number of jobs that panicked, since the pool was created.
+    pub panicked: u64,
+    /// The number of jobs that were cancelled before they started, since
+    /// the pool was created.
+    pub cancelled: u64,
+    /// The number of jobs that ran longer than their timeout, since the pool
+    /// was created. These jobs are counted again once they have finished.
+    pub timed_out: u64,
+    /// The number of workers an autoscaling pool started because jobs were
+    /// queued while all workers were busy.
+    pub scaled_up: u64,
+    /// The number of workers an autoscaling pool retired because they were
+    /// idle for the keep-alive time.
+    pub scaled_down: u64,
+    /// The number of workers started in place of workers that were stuck on
+    /// a job past its timeout, see `ThreadPoolBuilder::replace_stuck_workers`.
+    /// Stuck workers are not listed in `workers`.
+    pub replaced_workers: u64,
+    /// The workers of the pool, in the order they were started.
+    pub workers: Vec<WorkerStats>,
+    /// The tenants of the pool, in the order they first submitted a job. See
+    /// `ThreadPool::execute_for`.
+    pub tenants: Vec<TenantStats>,
+    /// How long jobs waited in the queue before they started. Empty unless
+    /// enabled with `ThreadPoolBuilder::job_timings`.
+    pub queue_wait: Histogram,
+    /// How long jobs ran. Empty unless enabled with
+    /// `ThreadPoolBuilder::job_timings`.
+    pub execution: Histogram,
+}
+
+/// The statistics of a single worker.
+#[derive(Clone, Debug)]
+pub struct WorkerStats {
+    /// The id of the worker, as passed to the thread callbacks and the panic
+    /// handler.
+    pub id: usize,
+    /// Whether the worker is running a job.
+    pub running: bool,
+    /// The number of jobs the worker has run.
+    pub jobs: u64,
+    /// The total time the worker was not waiting for work, that is, the
+    /// time it spent running jobs or looking for the next one. Zero unless
+    /// enabled with `ThreadPoolBuilder::job_timings`.
+    pub busy: Duration,
+}
+
+/// The statistics of a single tenant.
+#[derive(Clone, Debug)]
+pub struct TenantStats {
+    /// The name the tenant's jobs are submitted with.
+    pub name: String,
+    /// The tenant's weight, see `ThreadPool::set_tenant_weight`.
+    pub weight: u32,
+    /// The limit on the tenant's queued and running jobs, if any.
+    pub limit: Option<usize>,
+    /// The number of the tenant's jobs waiting in the queues, including jobs
+    /// held back by its limit.
+    pub queued: usize,
+    /// The number of the tenant's jobs that are running.
+    pub running: usize,
+    /// The number of the tenant's jobs that returned.
+    pub completed: u64,
+    /// The number of the tenant's jobs that panicked.
+    pub panicked: u64,
+    /// The number of the tenant's jobs that were cancelled before they
+    /// started.
+    pub cancelled: u64,
+}
+
+/// A histogram of durations.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    /// The upper bound of each bucket and the number of samples in it, in
+    /// increasing order of the bounds. A sample is counted in the first
+    /// bucket whose bound is not less than it, the bound of the last bucket
+    /// is `Duration::MAX`.
+    pub buckets: Vec<(Duration, u64)>,
+    /// The number of samples.
+    pub count: u64,
+    /// The sum of all samples.
+    pub sum: Duration,
+}
+
+/// A histogram that can be updated concurrently.
+#[derive(Default)]
+pub(crate) struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub(crate) fn record(&self, sample: Duration) {
+        let micros = sample.as_nanos().div_ceil(1000);
+        let index = if micros <= 1 {
+            0
+        } else {
+            (u128::BITS - (micros - 1).leading_zeros()) as usize
+        };
+        self.buckets[index.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(sample.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let buckets: Vec<(Duration, u64)> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(index, count)| (bucket_bound(index), count.load(Ordering::Relaxed)))
+            .collect();
+        Histogram {
+            count: buckets.iter().map(|&(_, count)| count).sum(),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// What happened to a job that was taken from the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Completed,
+    Panicked,
+    Cancelled,
+}
+
+/// The counters of a pool.
+pub(crate) struct Metrics {
+    /// Whether to fill the histograms and measure the workers' busy time.
+    pub(crate) timings: bool,
+    /// The point in time the workers' busy times are measured from.
+    epoch: Instant,
+    pub(crate) completed: AtomicU64,
+    pub(crate) panicked: AtomicU64,
+    pub(crate) cancelled: AtomicU64,
+    pub(crate) timed_out: AtomicU64,
+    pub(crate) scaled_up: AtomicU64,
+    pub(crate) scaled_down: AtomicU64,
+    pub(crate) replaced_workers: AtomicU64,
+    pub(crate) queue_wait: AtomicHistogram,
+    pub(crate) execution: AtomicHistogram,
+}
+
+impl Metrics {
+    pub(crate) fn new(timings: bool) -> Metrics {
+        Metrics {
+            timings,
+            epoch: Instant::now(),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            scaled_up: AtomicU64::new(0),
+            scaled_down: AtomicU64::new(0),
+            replaced_workers: AtomicU64::new(0),
+            queue_wait: AtomicHistogram::default(),
+            execution: AtomicHistogram::default(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    pub(crate) fn record(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Completed => &self.completed,
+            Outcome::Panicked => &self.panicked,
+            Outcome::Cancelled => &self.cancelled,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters of a single worker.
+///
+/// Only the worker itself updates its counters, so they are written with a
+/// plain load and store instead of a read-modify-write, which is cheap enough
+/// to do for every job.
+///
+/// Busy time is only measured when the worker starts or stops waiting for
+/// work, and only with job timings enabled, because every measurement reads
+/// the clock.
+#[derive(Default)]
+pub(crate) struct WorkerMetrics {
+    /// The number of jobs the worker is running. More than one when a job
+    /// runs other jobs while it waits for them.
+    running: AtomicUsize,
+    jobs: AtomicU64,
+    /// The busy time up to the last time the worker started waiting.
+    busy_nanos: AtomicU64,
+    /// When the worker stopped waiting, in nanoseconds since
+    /// `Metrics::epoch`, plus one. Zero while it is waiting.
+    busy_since: AtomicU64,
+}
+
+impl WorkerMetrics {
+    /// Called by the worker before it runs a job.
+    pub(crate) fn job_started(&self) {
+        let running = self.running.load(Ordering::Relaxed);
+        self.running.store(running + 1, Ordering::Relaxed);
+    }
+
+    /// Called by the worker after it ran a job.
+    pub(crate) fn job_finished(&self) {
+        let jobs = self.jobs.load(Ordering::Relaxed);
+        self.jobs.store(jobs + 1, Ordering::Relaxed);
+        let running = self.running.load(Ordering::Relaxed);
+        self.running.store(running - 1, Ordering::Relaxed);
+    }
+
+    /// Called when the worker starts, and when it stops waiting for work.
+    pub(crate) fn wake(&self, metrics: &Metrics) {
+        if metrics.timings {
+            self.busy_since.store(metrics.now() + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// Called when the worker starts waiting for work, and when it stops.
+    pub(crate) fn sleep(&self, metrics: &Metrics) {
+        if !metrics.timings {
+            return;
+        }
+        let since = self.busy_since.swap(0, Ordering::Relaxed);
+        if since > 0 {
+            let busy = metrics.now().saturating_sub(since - 1);
+            self.busy_nanos.fetch_add(busy, Ordering::Relaxed);
+        }
+    }
+
+    fn busy(&self, metrics: &Metrics) -> Duration {
+        let mut busy = self.busy_nanos.load(Ordering::Relaxed);
+        let since = self.busy_since.load(Ordering::Relaxed);
+        if since > 0 {
+            busy += metrics.now().saturating_sub(since - 1);
+        }
+        Duration::from_nanos(busy)
+    }
+}
+
+impl ThreadPool {
+    /// Returns a snapshot of the pool's queue, workers and job counters.
+    ///
+    /// The numbers are read one after the other while the pool keeps
+    /// running, so they may not add up exactly.
+    pub fn stats(&self) -> PoolStats {
+        let metrics = &self.shared.metrics;
+        let mut running = 0;
+        let workers: Vec<WorkerStats> = self
+            .shared
+            .workers
+            .lock()
+            .unwrap()
+            .active
+            .iter()
+            .map(|worker| {
+                let worker_metrics = &worker.local.metrics;
+                let worker_running = worker_metrics.running.load(Ordering::Relaxed);
+                running += worker_running;
+                WorkerStats {
+                    id: worker.id,
+                    running: worker_running > 0,
+                    jobs: worker_metrics.jobs.load(Ordering::Relaxed),
+                    busy: worker_metrics.busy(metrics),
+                }
+            })
+            .collect();
+
+        PoolStats {
+            queued: self.shared.scheduler.queued(),
+            running,
+            idle_workers: workers.iter().filter(|worker| !worker.running).count(),
+            completed: metrics.completed.load(Ordering::Relaxed),
+            panicked: metrics.panicked.load(Ordering::Relaxed),
+            cancelled: metrics.cancelled.load(Ordering::Relaxed),
+            timed_out: metrics.timed_out.load(Ordering::Relaxed),
+            scaled_up: metrics.scaled_up.load(Ordering::Relaxed),
+            scaled_down: metrics.scaled_down.load(Ordering::Relaxed),
+            replaced_workers: metrics.replaced_workers.load(Ordering::Relaxed),
+            workers,
+            tenants: self.shared.tenants.stats(),
+            queue_wait: metrics.queue_wait.snapshot(),
+            execution: metrics.execution.snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::AtomicHistogram;
+    use crate::tests::occupy;
+    use crate::{ThreadPool, ThreadPoolBuilder};
+
+    #[test]
+    fn histogram_buckets() {
+        let histogram = AtomicHistogram::default();
+        for nanos in [0, 1_000, 1_500, 3_000, 10_000_000_000] {
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.sum, Duration::from_nanos(10_000_005_500));
+        assert_eq!(snapshot.buckets[0], (Duration::from_micros(1), 2));
+        assert_eq!(snapshot.buckets[1], (Duration::from_micros(2), 1));
+        assert_eq!(snapshot.buckets[2], (Duration::from_micros(4), 1));
+        assert_eq!(snapshot.buckets.last(), Some(&(Duration::MAX, 1)));
+    }
+
+    #[test]
+    fn stats_count_jobs_by_outcome() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .job_timings(true)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+        pool.execute(|| ());
+        pool.execute(|| panic!("job panicked"));
+        assert!(pool.submit(|| ()).cancel());
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.idle_workers), (2, 1, 0));
+
+        drop(release);
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.idle_workers), (0, 0, 1));
+        assert_eq!(stats.completed, 2);
+        assert_eq!(stats.panicked, 1);
+        assert_eq!(stats.cancelled, 1);
+        assert_eq!(stats.queue_wait.count, 3);
+        assert_eq!(stats.execution.count, 3);
+        assert_eq!(stats.workers.len(), 1);
+        assert_eq!((stats.workers[0].id, stats.workers[0].jobs), (1, 3));
+    }
+
+    #[test]
+    fn busy_time_is_only_measured_with_job_timings() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| thread::sleep(Duration::from_millis(20)));
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert_eq!(stats.workers[0].busy, Duration::ZERO);
+        assert_eq!(stats.execution.count, 0);
+
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .job_timings(true)
+            .build()
+            .unwrap();
+        pool.execute(|| thread::sleep(Duration::from_millis(20)));
+        pool.wait_idle();
+        let stats = pool.stats();
+        assert!(stats.workers[0].busy >= Duration::from_millis(20));
+        assert_eq!(stats.execution.count, 1);
+    }
+}
@@ -0,0 +1,458 @@
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+use crate::{Histogram, PoolStats, TenantStats};
+
+/// Renders the statistics of one or more pools in the OpenMetrics text
+/// format, as served to Prometheus. Each pool is given as its name and a
+/// snapshot from `ThreadPool::stats`, and the name is added to every sample
+/// as the `pool` label.
+///
+/// The result is a complete exposition, ending with `# EOF`. Serve it with
+/// the content type
+/// `application/openmetrics-text; version=1.0.0; charset=utf-8`.
+pub fn render_openmetrics<'a, I>(pools: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, &'a PoolStats)>,
+{
+    let pools: Vec<(String, &PoolStats)> = pools
+        .into_iter()
+        .map(|(name, stats)| (escape(name), stats))
+        .collect();
+    let mut out = String::new();
+    // Writing to a `String` cannot fail.
+    let _ = write_metrics(&mut out, &pools);
+    out
+}
+
+impl PoolStats {
+    /// Renders the statistics in the OpenMetrics text format, labelled with
+    /// `pool_name`. See `render_openmetrics`, which can render several pools
+    /// at once.
+    pub fn to_openmetrics(&self, pool_name: &str) -> String {
+        render_openmetrics(Some((pool_name, self)))
+    }
+}
+
+fn write_metrics(out: &mut String, pools: &[(String, &PoolStats)]) -> fmt::Result {
+    write_gauge(
+        out,
+        "threadpool_queued_jobs",
+        "Jobs waiting in the queue.",
+        pools,
+        |stats| stats.queued,
+    )?;
+    write_gauge(
+        out,
+        "threadpool_running_jobs",
+        "Jobs that are running.",
+        pools,
+        |stats| stats.running,
+    )?;
+    write_gauge(
+        out,
+        "threadpool_workers",
+        "Workers of the pool.",
+        pools,
+        |stats| stats.workers.len(),
+    )?;
+    write_gauge(
+        out,
+        "threadpool_idle_workers",
+        "Workers that are not running a job.",
+        pools,
+        |stats| stats.idle_workers,
+    )?;
+
+    writeln!(out, "# TYPE threadpool_jobs counter")?;
+    writeln!(
+        out,
+        "# HELP threadpool_jobs Jobs that finished, by outcome."
+    )?;
+    for (pool, stats) in pools {
+        let outcomes = [
+            ("completed", stats.completed),
+            ("panicked", stats.panicked),
+            ("cancelled", stats.cancelled),
+        ];
+        for (outcome, count) in outcomes.iter() {
+            writeln!(
+                out,
+                "threadpool_jobs_total{{pool=\"{}\",outcome=\"{}\"}} {}",
+                pool, outcome, count
+            )?;
+        }
+    }
+
+    writeln!(out, "# TYPE threadpool_scaled_workers counter")?;
+    writeln!(
+        out,
+        "# HELP threadpool_scaled_workers Workers started or retired by autoscaling."
+    )?;
+    for (pool, stats) in pools {
+        let directions = [("up", stats.scaled_up), ("down", stats.scaled_down)];
+        for (direction, count) in directions.iter() {
+            writeln!(
+                out,
+                "threadpool_scaled_workers_total{{pool=\"{}\",direction=\"{}\"}} {}",
+                pool, direction, count
+            )?;
+        }
+    }
+
+    write_counter(
+        out,
+        "threadpool_timed_out_jobs",
+        "Jobs that ran longer than their timeout.",
+        pools,
+        |stats| stats.timed_out,
+    )?;
+    write_counter(
+        out,
+        "threadpool_replaced_workers",
+        "Workers started in place of a worker stuck on a job.",
+        pools,
+        |stats| stats.replaced_workers,
+    )?;
+
+    writeln!(out, "# TYPE threadpool_worker_jobs counter")?;
+    writeln!(
+        out,
+        "# HELP threadpool_worker_jobs Jobs run by each worker."
+    )?;
+    for (pool, stats) in pools {
+        for worker in &stats.workers {
+            writeln!(
+                out,
+                "threadpool_worker_jobs_total{{pool=\"{}\",worker=\"{}\"}} {}",
+                pool, worker.id, worker.jobs
+            )?;
+        }
+    }
+
+    writeln!(out, "# TYPE threadpool_worker_busy_seconds counter")?;
+    writeln!(out, "# UNIT threadpool_worker_busy_seconds seconds")?;
+    writeln!(
+        out,
+        "# HELP threadpool_worker_busy_seconds Time each worker was not waiting for work."
+    )?;
+    for (pool, stats) in pools {
+        for worker in &stats.workers {
+            writeln!(
+                out,
+                "threadpool_worker_busy_seconds_total{{pool=\"{}\",worker=\"{}\"}} {}",
+                pool,
+                worker.id,
+                worker.busy.as_secs_f64()
+            )?;
+        }
+    }
+
+    write_tenant_gauge(
+        out,
+        "threadpool_tenant_queued_jobs",
+        "Jobs of each tenant waiting in the queue.",
+        pools,
+        |tenant| tenant.queued,
+    )?;
+    write_tenant_gauge(
+        out,
+        "threadpool_tenant_running_jobs",
+        "Jobs of each tenant that are running.",
+        pools,
+        |tenant| tenant.running,
+    )?;
+
+    writeln!(out, "# TYPE threadpool_tenant_jobs counter")?;
+    writeln!(
+        out,
+        "# HELP threadpool_tenant_jobs Jobs of each tenant that finished, by outcome."
+    )?;
+    for (pool, stats) in pools {
+        for tenant in &stats.tenants {
+            let name = escape(&tenant.name);
+            let outcomes = [
+                ("completed", tenant.completed),
+                ("panicked", tenant.panicked),
+                ("cancelled", tenant.cancelled),
+            ];
+            for (outcome, count) in outcomes.iter() {
+                writeln!(
+                    out,
+                    "threadpool_tenant_jobs_total{{pool=\"{}\",tenant=\"{}\",outcome=\"{}\"}} {}",
+                    pool, name, outcome, count
+                )?;
+            }
+        }
+    }
+
+    write_histogram(
+        out,
+        "threadpool_queue_wait_seconds",
+        "Time jobs waited in the queue.",
+        pools,
+        |stats| &stats.queue_wait,
+    )?;
+    write_histogram(
+        out,
+        "threadpool_execution_seconds",
+        "Time jobs ran.",
+        pools,
+        |stats| &stats.execution,
+    )?;
+
+    writeln!(out, "# EOF")
+}
+
+fn write_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    pools: &[(String, &PoolStats)],
+    value: fn(&PoolStats) -> usize,
+) -> fmt::Result {
+    writeln!(out, "# TYPE {} gauge", name)?;
+    writeln!(out, "# HELP {} {}", name, help)?;
+    for (pool, stats) in pools {
+        writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool, value(stats))?;
+    }
+    Ok(())
+}
+
+fn write_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    pools: &[(String, &PoolStats)],
+    value: fn(&PoolStats) -> u64,
+) -> fmt::Result {
+    writeln!(out, "# TYPE {} counter", name)?;
+    writeln!(out, "# HELP {} {}", name, help)?;
+    for (pool, stats) in pools {
+        writeln!(out, "{}_total{{pool=\"{}\"}} {}", name, pool, value(stats))?;
+    }
+    Ok(())
+}
+
+fn write_tenant_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    pools: &[(String, &PoolStats)],
+    value: fn(&TenantStats) -> usize,
+) -> fmt::Result {
+    writeln!(out, "# TYPE {} gauge", name)?;
+    writeln!(out, "# HELP {} {}", name, help)?;
+    for (pool, stats) in pools {
+        for tenant in &stats.tenants {
+            writeln!(
+                out,
+                "{}{{pool=\"{}\",tenant=\"{}\"}} {}",
+                name,
+                pool,
+                escape(&tenant.name),
+                value(tenant)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    pools: &[(String, &PoolStats)],
+    histogram: fn(&PoolStats) -> &Histogram,
+) -> fmt::Result {
+    writeln!(out, "# TYPE {} histogram", name)?;
+    writeln!(out, "# UNIT {} seconds", name)?;
+    writeln!(out, "# HELP {} {}", name, help)?;
+    for (pool, stats) in pools {
+        let histogram = histogram(stats);
+        // OpenMetrics buckets are cumulative.
+        let mut count = 0;
+        for &(bound, samples) in &histogram.buckets {
+            count += samples;
+            if bound == Duration::MAX {
+                continue;
+            }
+            writeln!(
+                out,
+                "{}_bucket{{pool=\"{}\",le=\"{}\"}} {}",
+                name,
+                pool,
+                bound.as_secs_f64(),
+                count
+            )?;
+        }
+        writeln!(
+            out,
+            "{}_bucket{{pool=\"{}\",le=\"+Inf\"}} {}",
+            name, pool, histogram.count
+        )?;
+        writeln!(
+            out,
+            "{}_count{{pool=\"{}\"}} {}",
+            name, pool, histogram.count
+        )?;
+        writeln!(
+            out,
+            "{}_sum{{pool=\"{}\"}} {}",
+            name,
+            pool,
+            histogram.sum.as_secs_f64()
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::render_openmetrics;
+    use crate::{Histogram, PoolStats, TenantStats, WorkerStats};
+
+    fn stats() -> PoolStats {
+        PoolStats {
+            queued: 2,
+            running: 1,
+            idle_workers: 1,
+            completed: 10,
+            panicked: 1,
+            cancelled: 3,
+            timed_out: 0,
+            scaled_up: 1,
+            scaled_down: 0,
+            replaced_workers: 0,
+            workers: vec![
+                WorkerStats {
+                    id: 1,
+                    running: true,
+                    jobs: 8,
+                    busy: Duration::from_millis(1500),
+                },
+                WorkerStats {
+                    id: 2,
+                    running: false,
+                    jobs: 3,
+                    busy: Duration::ZERO,
+                },
+            ],
+            tenants: vec![TenantStats {
+                name: "a\"b".to_string(),
+                weight: 1,
+                limit: Some(2),
+                queued: 1,
+                running: 1,
+                completed: 4,
+                panicked: 0,
+                cancelled: 1,
+            }],
+            queue_wait: Histogram {
+                buckets: vec![
+                    (Duration::from_micros(1), 1),
+                    (Duration::from_micros(2), 2),
+                    (Duration::MAX, 1),
+                ],
+                count: 4,
+                sum: Duration::from_micros(2500),
+            },
+            execution: Histogram::default(),
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let expected = r#"# TYPE threadpool_queued_jobs gauge
+# HELP threadpool_queued_jobs Jobs waiting in the queue.
+threadpool_queued_jobs{pool="web\n1"} 2
+# TYPE threadpool_running_jobs gauge
+# HELP threadpool_running_jobs Jobs that are running.
+threadpool_running_jobs{pool="web\n1"} 1
+# TYPE threadpool_workers gauge
+# HELP threadpool_workers Workers of the pool.
+threadpool_workers{pool="web\n1"} 2
+# TYPE threadpool_idle_workers gauge
+# HELP threadpool_idle_workers Workers that are not running a job.
+threadpool_idle_workers{pool="web\n1"} 1
+# TYPE threadpool_jobs counter
+# HELP threadpool_jobs Jobs that finished, by outcome.
+threadpool_jobs_total{pool="web\n1",outcome="completed"} 10
+threadpool_jobs_total{pool="web\n1",outcome="panicked"} 1
+threadpool_jobs_total{pool="web\n1",outcome="cancelled"} 3
+# TYPE threadpool_scaled_workers counter
+# HELP threadpool_scaled_workers Workers started or retired by autoscaling.
+threadpool_scaled_workers_total{pool="web\n1",direction="up"} 1
+threadpool_scaled_workers_total{pool="web\n1",direction="down"} 0
+# TYPE threadpool_timed_out_jobs counter
+# HELP threadpool_timed_out_jobs Jobs that ran longer than their timeout.
+threadpool_timed_out_jobs_total{pool="web\n1"} 0
+# TYPE threadpool_replaced_workers counter
+# HELP threadpool_replaced_workers Workers started in place of a worker stuck on a job.
+threadpool_replaced_workers_total{pool="web\n1"} 0
+# TYPE threadpool_worker_jobs counter
+# HELP threadpool_worker_jobs Jobs run by each worker.
+threadpool_worker_jobs_total{pool="web\n1",worker="1"} 8
+threadpool_worker_jobs_total{pool="web\n1",worker="2"} 3
+# TYPE threadpool_worker_busy_seconds counter
+# UNIT threadpool_worker_busy_seconds seconds
+# HELP threadpool_worker_busy_seconds Time each worker was not waiting for work.
+threadpool_worker_busy_seconds_total{pool="web\n1",worker="1"} 1.5
+threadpool_worker_busy_seconds_total{pool="web\n1",worker="2"} 0
+# TYPE threadpool_tenant_queued_jobs gauge
+# HELP threadpool_tenant_queued_jobs Jobs of each tenant waiting in the queue.
+threadpool_tenant_queued_jobs{pool="web\n1",tenant="a\"b"} 1
+# TYPE threadpool_tenant_running_jobs gauge
+# HELP threadpool_tenant_running_jobs Jobs of each tenant that are running.
+threadpool_tenant_running_jobs{pool="web\n1",tenant="a\"b"} 1
+# TYPE threadpool_tenant_jobs counter
+# HELP threadpool_tenant_jobs Jobs of each tenant that finished, by outcome.
+threadpool_tenant_jobs_total{pool="web\n1",tenant="a\"b",outcome="completed"} 4
+threadpool_tenant_jobs_total{pool="web\n1",tenant="a\"b",outcome="panicked"} 0
+threadpool_tenant_jobs_total{pool="web\n1",tenant="a\"b",outcome="cancelled"} 1
+# TYPE threadpool_queue_wait_seconds histogram
+# UNIT threadpool_queue_wait_seconds seconds
+# HELP threadpool_queue_wait_seconds Time jobs waited in the queue.
+threadpool_queue_wait_seconds_bucket{pool="web\n1",le="0.000001"} 1
+threadpool_queue_wait_seconds_bucket{pool="web\n1",le="0.000002"} 3
+threadpool_queue_wait_seconds_bucket{pool="web\n1",le="+Inf"} 4
+threadpool_queue_wait_seconds_count{pool="web\n1"} 4
+threadpool_queue_wait_seconds_sum{pool="web\n1"} 0.0025
+# TYPE threadpool_execution_seconds histogram
+# UNIT threadpool_execution_seconds seconds
+# HELP threadpool_execution_seconds Time jobs ran.
+threadpool_execution_seconds_bucket{pool="web\n1",le="+Inf"} 0
+threadpool_execution_seconds_count{pool="web\n1"} 0
+threadpool_execution_seconds_sum{pool="web\n1"} 0
+# EOF
+"#;
+        assert_eq!(stats().to_openmetrics("web\n1"), expected);
+    }
+
+    #[test]
+    fn several_pools_share_one_exposition() {
+        let (a, b) = (stats(), stats());
+        let text = render_openmetrics(vec![("a", &a), ("b", &b)]);
+        assert_eq!(text.matches("# TYPE threadpool_queued_jobs ").count(), 1);
+        assert!(text.contains("threadpool_queued_jobs{pool=\"a\"} 2\n"));
+        assert!(text.contains("threadpool_queued_jobs{pool=\"b\"} 2\n"));
+        assert_eq!(text.matches("# EOF").count(), 1);
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
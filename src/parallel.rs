@@ -0,0 +1,294 @@
+use crate::ThreadPool;
+
+/// The number of chunks per worker when the chunk size is not set. More than
+/// one, so workers that finish early can take over chunks of slower ones.
+const CHUNKS_PER_WORKER: usize = 4;
+
+/// Data-parallel operations on a pool, with a chunk size. See
+/// `ThreadPool::parallel`.
+///
+/// The items are collected on the calling thread and split into chunks, and
+/// each chunk runs as one job. Smaller chunks balance uneven work better,
+/// larger ones have less overhead per item. If all items fit in a single
+/// chunk, it runs on the calling thread.
+///
+/// Called from a job on the same pool, the worker runs queued jobs while it
+/// waits for the chunks, as in `ThreadPool::scope`.
+#[derive(Clone, Copy)]
+pub struct Parallel<'pool> {
+    pool: &'pool ThreadPool,
+    chunk_size: Option<usize>,
+}
+
+impl<'pool> Parallel<'pool> {
+    /// Sets the number of items per job. By default, the items are split into
+    /// a few chunks per worker.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `chunk_size` is zero.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Parallel<'pool> {
+        assert!(chunk_size > 0, "chunk size must be greater than zero");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Calls `f` on every item and returns the results in the order of the
+    /// items.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is resumed here once all chunks have finished.
+    /// This also panics if the pool is shutting down.
+    pub fn map<I, F, U>(self, items: I, f: F) -> Vec<U>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> U + Sync,
+        U: Send,
+    {
+        self.run(items, |chunk| chunk.into_iter().map(&f).collect::<Vec<_>>())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Calls `f` on every item, in no particular order.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `map`.
+    pub fn for_each<I, F>(self, items: I, f: F)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        self.run(items, |chunk| chunk.into_iter().for_each(&f));
+    }
+
+    /// Calls `f` on every item and returns the results that are `Some`, in
+    /// the order of the items.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `map`.
+    pub fn filter_map<I, F, U>(self, items: I, f: F) -> Vec<U>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> Option<U> + Sync,
+        U: Send,
+    {
+        self.run(items, |chunk| {
+            chunk.into_iter().filter_map(&f).collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Combines all items into one with `f`, like `Iterator::reduce`. Returns
+    /// `None` if there are no items.
+    ///
+    /// Each chunk is reduced on its own, and then the results of the chunks,
+    /// so `f` has to be associative. The items are always combined in their
+    /// order, so it need not be commutative.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `map`.
+    pub fn reduce<I, F>(self, items: I, f: F) -> Option<I::Item>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item, I::Item) -> I::Item + Sync,
+    {
+        self.run(items, |chunk| chunk.into_iter().reduce(&f))
+            .into_iter()
+            .flatten()
+            .reduce(&f)
+    }
+
+    /// Splits `items` into chunks, runs `job` on each and returns the results
+    /// in the order of the chunks.
+    fn run<I, J, R>(self, items: I, job: J) -> Vec<R>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        J: Fn(Vec<I::Item>) -> R + Sync,
+        R: Send,
+    {
+        let items: Vec<I::Item> = items.into_iter().collect();
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let chunk_size = self.chunk_size.unwrap_or_else(|| {
+            let chunks = self.pool.thread_count().max(1) * CHUNKS_PER_WORKER;
+            items.len().div_ceil(chunks)
+        });
+        if items.len() <= chunk_size {
+            return vec![job(items)];
+        }
+
+        let mut chunks = Vec::with_capacity(items.len().div_ceil(chunk_size));
+        let mut items = items.into_iter();
+        while items.len() > 0 {
+            chunks.push(items.by_ref().take(chunk_size).collect::<Vec<_>>());
+        }
+
+        let mut results: Vec<Option<R>> = chunks.iter().map(|_| None).collect();
+        let job = &job;
+        self.pool.scope(|scope| {
+            for (chunk, result) in chunks.into_iter().zip(&mut results) {
+                scope.spawn(move || *result = Some(job(chunk)));
+            }
+        });
+        // The scope resumes any panic, so every chunk has a result.
+        results.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+impl ThreadPool {
+    /// Returns the data-parallel operations of the pool, to run them with a
+    /// chunk size. `par_map` and the other shorthands split the items into
+    /// a few chunks per worker.
+    pub fn parallel(&self) -> Parallel<'_> {
+        Parallel {
+            pool: self,
+            chunk_size: None,
+        }
+    }
+
+    /// Calls `f` on every item on the pool's workers and returns the results
+    /// in the order of the items. See `Parallel::map`.
+    pub fn par_map<I, F, U>(&self, items: I, f: F) -> Vec<U>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> U + Sync,
+        U: Send,
+    {
+        self.parallel().map(items, f)
+    }
+
+    /// Calls `f` on every item on the pool's workers, in no particular order.
+    /// See `Parallel::for_each`.
+    pub fn par_for_each<I, F>(&self, items: I, f: F)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        self.parallel().for_each(items, f)
+    }
+
+    /// Calls `f` on every item on the pool's workers and returns the results
+    /// that are `Some`, in the order of the items. See
+    /// `Parallel::filter_map`.
+    pub fn par_filter_map<I, F, U>(&self, items: I, f: F) -> Vec<U>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> Option<U> + Sync,
+        U: Send,
+    {
+        self.parallel().filter_map(items, f)
+    }
+
+    /// Combines all items into one with the associative function `f` on the
+    /// pool's workers. See `Parallel::reduce`.
+    pub fn par_reduce<I, F>(&self, items: I, f: F) -> Option<I::Item>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item, I::Item) -> I::Item + Sync,
+    {
+        self.parallel().reduce(items, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use crate::ThreadPool;
+
+    #[test]
+    fn results_keep_the_order_of_the_items() {
+        let pool = ThreadPool::new(4);
+        let expected: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        assert_eq!(pool.par_map(0..1000u64, |i| i * i), expected);
+        for chunk_size in [1, 7, 1000, 5000] {
+            let parallel = pool.parallel().chunk_size(chunk_size);
+            assert_eq!(parallel.map(0..1000u64, |i| i * i), expected);
+        }
+
+        let even: Vec<u64> = (0..1000).filter(|i| i % 2 == 0).collect();
+        let filtered = pool.par_filter_map(0..1000u64, |i| (i % 2 == 0).then_some(i));
+        assert_eq!(filtered, even);
+    }
+
+    #[test]
+    fn reduce_combines_the_items_in_order() {
+        let pool = ThreadPool::new(4);
+        let items: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let expected: String = items.concat();
+        let joined = pool.parallel().chunk_size(3).reduce(items, |a, b| a + &b);
+        assert_eq!(joined, Some(expected));
+        assert_eq!(pool.par_reduce(Vec::<u32>::new(), |a, b| a + b), None);
+    }
+
+    #[test]
+    fn for_each_visits_every_item_once() {
+        let pool = ThreadPool::new(4);
+        let sum = AtomicUsize::new(0);
+        pool.par_for_each(1..=100, |i| {
+            sum.fetch_add(i, Ordering::SeqCst);
+        });
+        assert_eq!(sum.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    fn each_chunk_runs_as_one_job() {
+        let pool = ThreadPool::new(2);
+        pool.parallel().chunk_size(10).for_each(0..95, |_| ());
+        pool.wait_idle();
+        assert_eq!(pool.stats().completed, 10);
+
+        // A single chunk runs on the calling thread.
+        let caller = thread::current().id();
+        let threads = pool
+            .parallel()
+            .chunk_size(100)
+            .map(0..50, |_| thread::current().id());
+        assert!(threads.iter().all(|&id| id == caller));
+        pool.wait_idle();
+        assert_eq!(pool.stats().completed, 10);
+    }
+
+    #[test]
+    fn panic_is_resumed_after_all_chunks_finished() {
+        let pool = ThreadPool::new(2);
+        let visited = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.parallel().chunk_size(1).for_each(0..20, |i| {
+                visited.fetch_add(1, Ordering::SeqCst);
+                if i == 3 {
+                    panic!("item panicked");
+                }
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(visited.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunk_size_must_not_be_zero() {
+        let pool = ThreadPool::new(1);
+        let _ = pool.parallel().chunk_size(0);
+    }
+}
@@ -0,0 +1,165 @@
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::scheduler::LocalQueue;
+use crate::{trace, Shared};
+
+/// The bounds of an autoscaling pool, see `ThreadPoolBuilder::max_threads`.
+pub(crate) struct Scaling {
+    pub(crate) min: usize,
+    pub(crate) max: usize,
+    pub(crate) keep_alive: Duration,
+}
+
+impl Shared {
+    /// Starts another worker if jobs are queued and no worker is free to
+    /// take them, unless the pool has its maximum number of workers.
+    pub(crate) fn grow(self: &Arc<Shared>) {
+        let scaling = match &self.scaling {
+            Some(scaling) => scaling,
+            None => return,
+        };
+        if !self.scheduler.is_backed_up() {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap();
+        workers.reap();
+        let count = workers.active.len();
+        // Checked again under the lock. A worker that was just started counts
+        // as searching, so concurrent callers do not start one each.
+        if count >= scaling.max || self.scheduler.is_shut_down() || !self.scheduler.is_backed_up() {
+            return;
+        }
+        match workers.spawn(self) {
+            Ok(id) => {
+                debug!("Started worker {} for queued jobs.", id);
+                self.metrics.scaled_up.fetch_add(1, Ordering::Relaxed);
+                trace::resized(count, count + 1);
+            }
+            Err(err) => error!("Failed to start a worker for queued jobs: {}", err),
+        }
+    }
+
+    /// Retires the worker that owns `local` after it has been idle for the
+    /// keep-alive time, unless that would leave the pool with fewer than its
+    /// minimum number of workers. Returns whether the worker retired.
+    pub(crate) fn shrink(&self, local: &LocalQueue) -> bool {
+        let scaling = match &self.scaling {
+            Some(scaling) => scaling,
+            None => return false,
+        };
+
+        let mut workers = self.workers.lock().unwrap();
+        workers.reap();
+        let count = workers.active.len();
+        if count <= scaling.min || self.scheduler.queued() > 0 {
+            return false;
+        }
+        // The worker is missing if it was retired by `resize` or taken by a
+        // shutdown in the meantime.
+        let index = match workers
+            .active
+            .iter()
+            .position(|worker| ptr::eq(&*worker.local, local))
+        {
+            Some(index) => index,
+            None => return false,
+        };
+        let worker = workers.active.remove(index);
+        self.scheduler.retire(local);
+        // A thread cannot join itself, so the worker is joined later.
+        workers.retired.push(worker);
+
+        debug!(
+            "Worker {} was idle for {:?}, retiring.",
+            local.id, scaling.keep_alive
+        );
+        self.metrics.scaled_down.fetch_add(1, Ordering::Relaxed);
+        trace::resized(count, count - 1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::{ThreadPool, ThreadPoolBuilder, ThreadPoolError};
+
+    /// Waits up to ten seconds for `pool` to have `count` threads.
+    fn wait_for_thread_count(pool: &ThreadPool, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.thread_count() != count {
+            assert!(Instant::now() < deadline, "{:?}", pool.stats());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn grows_when_busy_and_shrinks_when_idle() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .max_threads(4)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert_eq!(pool.thread_count(), 1);
+        // The jobs can only pass the barrier once four workers run them.
+        let barrier = Arc::new(Barrier::new(5));
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        pool.wait_idle();
+        assert_eq!(pool.stats().scaled_up, 3);
+
+        wait_for_thread_count(&pool, 1);
+        assert_eq!(pool.stats().scaled_down, 3);
+        assert_eq!(pool.submit(|| 5).join().unwrap(), 5);
+    }
+
+    #[test]
+    fn scales_down_to_zero_threads() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(0)
+            .max_threads(2)
+            .keep_alive(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        assert_eq!(pool.thread_count(), 0);
+        assert_eq!(pool.submit(|| 1).join().unwrap(), 1);
+        wait_for_thread_count(&pool, 0);
+        assert_eq!(pool.submit(|| 2).join().unwrap(), 2);
+    }
+
+    #[test]
+    fn thread_range_is_checked() {
+        assert!(matches!(
+            ThreadPoolBuilder::new()
+                .min_threads(3)
+                .max_threads(2)
+                .build(),
+            Err(ThreadPoolError::InvalidThreadRange { min: 3, max: 2 })
+        ));
+        assert!(matches!(
+            ThreadPoolBuilder::new().max_threads(0).build(),
+            Err(ThreadPoolError::ZeroThreads)
+        ));
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(10)
+            .max_threads(2)
+            .build()
+            .unwrap();
+        assert_eq!(pool.thread_count(), 2);
+    }
+}
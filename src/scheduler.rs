@@ -0,0 +1,1097 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::metrics::WorkerMetrics;
+use crate::trace::Context;
+use crate::{CancellationToken, Job, TryExecuteError};
+
+thread_local! {
+    /// The scheduler and local queue of the worker running on this thread,
+    /// if any. The scheduler is identified by its address.
+    static CURRENT: RefCell<Option<(usize, Arc<LocalQueue>)>> = const { RefCell::new(None) };
+}
+
+/// The queue that jobs are submitted to unless they name another one.
+pub(crate) const DEFAULT_QUEUE: usize = 0;
+
+/// The virtual time a queue with weight 1 takes up per job, see
+/// `WeightedQueue`.
+const STRIDE: u128 = 1 << 32;
+
+/// The priority of a job. Workers take the queued job with the highest
+/// priority first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Only run when there are no other queued jobs, unless aged.
+    Low,
+    /// The priority of jobs submitted with `ThreadPool::execute`.
+    #[default]
+    Normal,
+    /// Run before all other queued jobs.
+    High,
+}
+
+/// A queued job, together with what the scheduler needs to know about it.
+///
+/// `F` is the unboxed job while it is being pushed, so that it can be handed
+/// back to the submitter if it cannot be queued.
+pub(crate) struct Task<F = Job> {
+    pub(crate) job: F,
+    /// Whether the job borrows from a `Scope`. Such jobs must never be handed
+    /// out of the pool.
+    pub(crate) scoped: bool,
+    pub(crate) priority: Priority,
+    /// The index of the weighted queue the job was submitted to.
+    pub(crate) queue: usize,
+    /// When the job was queued. Only recorded when it is needed for aging or
+    /// the queue wait histogram.
+    pub(crate) queued_at: Option<Instant>,
+    pub(crate) context: Context,
+    /// Identifies jobs that may be taken out of the queues again, see
+    /// `Scheduler::remove`: the jobs of a `JobHandle`, which carry its
+    /// cancellation token, and the jobs of `ThreadPool::join`.
+    pub(crate) token: Option<CancellationToken>,
+}
+
+impl<F> Task<F>
+where
+    F: FnOnce() + Send + 'static,
+{
+    pub(crate) fn new(job: F) -> Task<F> {
+        Task {
+            job,
+            scoped: false,
+            priority: Priority::Normal,
+            queue: DEFAULT_QUEUE,
+            queued_at: None,
+            context: Context::current(),
+            token: None,
+        }
+    }
+
+    pub(crate) fn boxed(self) -> Task {
+        self.map(|job| -> Job { Box::new(job) })
+    }
+
+    /// Replaces the job with `f` applied to it, and keeps everything else.
+    pub(crate) fn map<G>(self, f: impl FnOnce(F) -> G) -> Task<G> {
+        Task {
+            job: f(self.job),
+            scoped: self.scoped,
+            priority: self.priority,
+            queue: self.queue,
+            queued_at: self.queued_at,
+            context: self.context,
+            token: self.token,
+        }
+    }
+}
+
+/// What `Scheduler::push` does with a job when the injector is full.
+#[derive(Clone, Copy)]
+pub(crate) enum Overflow {
+    /// Wait until there is room.
+    Block,
+    /// Hand the job back.
+    Reject,
+    /// Drop the oldest job with the lowest priority in the injector. If all
+    /// queued jobs are scoped, which must not be dropped, wait until there is
+    /// room if `block` is set, or else hand the job back.
+    DropOldest { block: bool },
+    /// Queue the job anyway. For jobs that the pool accepted earlier, such as
+    /// held back jobs, see `Tenant`, and polls of spawned futures.
+    Force,
+}
+
+/// One of the queues of the injector, with a FIFO queue per priority level.
+///
+/// Jobs are taken from the queues in proportion to their weights by stride
+/// scheduling: taking a job advances the queue's pass by its stride, the
+/// inverse of its weight, and of the queues with jobs of the same priority
+/// the one with the lowest pass is next.
+struct WeightedQueue {
+    levels: [VecDeque<Task>; 3],
+    stride: u128,
+    pass: u128,
+}
+
+impl WeightedQueue {
+    fn new(weight: u32) -> WeightedQueue {
+        WeightedQueue {
+            levels: Default::default(),
+            stride: STRIDE / u128::from(weight),
+            pass: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(VecDeque::is_empty)
+    }
+}
+
+/// The injector queue. It is made up of the default queue, the named queues,
+/// see `ThreadPoolBuilder::queue`, and a queue per tenant.
+struct Injector {
+    queues: Vec<WeightedQueue>,
+    /// The indexes of the queues that have jobs, so that picking a job does
+    /// not look at every tenant that ever submitted one.
+    active: Vec<usize>,
+    len: usize,
+    /// The number of high priority jobs.
+    urgent: usize,
+    /// The pass of the queue a job was last taken from. A queue that was
+    /// empty starts from here once it has jobs again, so it cannot save up
+    /// turns while it is empty.
+    pass: u128,
+}
+
+impl Injector {
+    /// Creates an injector with a queue for each of `weights`.
+    fn new(weights: &[u32]) -> Injector {
+        Injector {
+            queues: weights
+                .iter()
+                .map(|&weight| WeightedQueue::new(weight))
+                .collect(),
+            active: Vec::new(),
+            len: 0,
+            urgent: 0,
+            pass: 0,
+        }
+    }
+
+    fn push(&mut self, task: Task) {
+        let queue = &mut self.queues[task.queue];
+        if queue.is_empty() {
+            queue.pass = queue.pass.max(self.pass);
+            self.active.push(task.queue);
+        }
+        self.len += 1;
+        if task.priority == Priority::High {
+            self.urgent += 1;
+        }
+        queue.levels[task.priority as usize].push_back(task);
+    }
+
+    /// Takes the oldest job at `lane`, a queue and priority level, and
+    /// charges the queue for it.
+    fn take(&mut self, lane: Lane) -> Option<Task> {
+        let task = self.pop(lane)?;
+        let queue = &mut self.queues[lane.0];
+        self.pass = queue.pass;
+        queue.pass += queue.stride;
+        Some(task)
+    }
+
+    fn pop(&mut self, lane: Lane) -> Option<Task> {
+        self.remove(lane, 0)
+    }
+
+    /// Removes the job at `position` in `lane`, without charging the queue.
+    fn remove(&mut self, (index, level): Lane, position: usize) -> Option<Task> {
+        let queue = &mut self.queues[index];
+        let task = queue.levels[level].remove(position)?;
+        if queue.is_empty() {
+            self.active.retain(|&active| active != index);
+        }
+        self.len -= 1;
+        if task.priority == Priority::High {
+            self.urgent -= 1;
+        }
+        Some(task)
+    }
+
+    /// Removes the oldest job with the lowest priority, from the first queue
+    /// that has one. Scoped jobs are never removed, since their scope would
+    /// panic.
+    fn evict(&mut self) -> Option<Task> {
+        let (lane, position) = (0..3).find_map(|level| {
+            self.active.iter().find_map(|&index| {
+                let jobs = &self.queues[index].levels[level];
+                let position = jobs.iter().position(|task| !task.scoped)?;
+                Some(((index, level), position))
+            })
+        })?;
+        self.remove(lane, position)
+    }
+
+    /// Returns the lane and position of the first job for which `f` returns
+    /// `true`.
+    fn find(&self, f: impl Fn(&Task) -> bool) -> Option<(Lane, usize)> {
+        self.active.iter().find_map(|&index| {
+            let levels = &self.queues[index].levels;
+            (0..3).find_map(|level| {
+                let position = levels[level].iter().position(&f)?;
+                Some(((index, level), position))
+            })
+        })
+    }
+
+    fn drain(&mut self) -> Vec<Task> {
+        let mut tasks = Vec::with_capacity(self.len);
+        for level in (0..3).rev() {
+            for &index in &self.active {
+                tasks.extend(self.queues[index].levels[level].drain(..));
+            }
+        }
+        self.active.clear();
+        self.len = 0;
+        self.urgent = 0;
+        tasks
+    }
+}
+
+/// A queue of the injector and a priority level in it.
+type Lane = (usize, usize);
+
+/// The job deque of a single worker.
+///
+/// The owning worker pushes and pops jobs at the back, so it runs the jobs it
+/// spawned itself most recently first. Other workers steal the oldest jobs
+/// from the front.
+pub(crate) struct LocalQueue {
+    /// The id of the owning worker.
+    pub(crate) id: usize,
+    jobs: Mutex<VecDeque<Task>>,
+    retire: AtomicBool,
+    /// Whether the owning worker has stopped taking jobs for good.
+    stopped: AtomicBool,
+    /// Whether the owning worker was woken up and has not found a job yet.
+    searching: AtomicBool,
+    pub(crate) metrics: WorkerMetrics,
+}
+
+impl LocalQueue {
+    pub(crate) fn is_retiring(&self) -> bool {
+        self.retire.load(Ordering::SeqCst)
+    }
+}
+
+/// A work-stealing scheduler.
+///
+/// Jobs submitted from outside the pool go into a shared injector queue. Jobs
+/// submitted from one of the workers go into that worker's local queue. Idle
+/// workers take jobs from their own queue first, then from the injector and
+/// finally steal from the other workers.
+///
+/// Jobs with a priority other than `Priority::Normal`, and jobs submitted to
+/// a named queue, always go into the injector. High priority jobs there are
+/// taken before the worker's own queue, low priority jobs only when there is
+/// nothing else to do. With aging, a job is raised one priority level for
+/// every `aging` it waits.
+///
+/// The injector can be bounded. Local queues are not, a worker that blocks on
+/// a full queue could otherwise wait for itself.
+pub(crate) struct Scheduler {
+    injector: Mutex<Injector>,
+    /// The names of the injector's queues, by index.
+    queue_names: Vec<String>,
+    /// The number of jobs in the injector, and how many of them have a high
+    /// priority, so workers can skip locking it. See `publish`.
+    injected: AtomicUsize,
+    urgent: AtomicUsize,
+    capacity: Option<usize>,
+    aging: Option<Duration>,
+    /// Whether to record when jobs are queued.
+    timestamps: bool,
+    /// Signalled when a job is taken from a bounded injector.
+    not_full: Condvar,
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    /// The number of jobs in the injector and all local queues.
+    queued: AtomicUsize,
+    /// The number of jobs that are queued or running.
+    unfinished: AtomicUsize,
+    /// The number of workers that are waiting for work and have not been
+    /// claimed by a wakeup. Only changed while holding `sleep`.
+    idle: AtomicUsize,
+    /// The number of workers that were woken up, or claimed by a wakeup, and
+    /// have not found a job yet. While there are any, new jobs do not wake up
+    /// more workers.
+    searching: AtomicUsize,
+    /// The number of wakeups that have been claimed but not yet taken by a
+    /// waiting worker.
+    sleep: Mutex<usize>,
+    wake: Condvar,
+    /// Signalled, together with `sleep`, when a worker stops.
+    stopped: Condvar,
+    /// Signalled, together with `sleep`, when the last unfinished job has
+    /// finished.
+    all_finished: Condvar,
+    shutdown: AtomicBool,
+    /// Set by `shutdown_now`. Workers stop taking jobs, and no jobs can be
+    /// queued anymore, not even by workers.
+    halt: AtomicBool,
+}
+
+impl Scheduler {
+    /// Creates a scheduler whose injector holds at most `capacity` jobs, or
+    /// any number of jobs if `capacity` is `None`. `timings` records when
+    /// jobs are queued even without aging. `queues` are the names and weights
+    /// of the injector's queues, starting with the default queue.
+    pub(crate) fn new(
+        capacity: Option<usize>,
+        aging: Option<Duration>,
+        timings: bool,
+        queues: Vec<(String, u32)>,
+    ) -> Scheduler {
+        let weights: Vec<u32> = queues.iter().map(|&(_, weight)| weight).collect();
+        Scheduler {
+            injector: Mutex::new(Injector::new(&weights)),
+            queue_names: queues.into_iter().map(|(name, _)| name).collect(),
+            injected: AtomicUsize::new(0),
+            urgent: AtomicUsize::new(0),
+            capacity,
+            aging,
+            timestamps: aging.is_some() || timings,
+            not_full: Condvar::new(),
+            locals: RwLock::new(Vec::new()),
+            queued: AtomicUsize::new(0),
+            unfinished: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            sleep: Mutex::new(0),
+            wake: Condvar::new(),
+            stopped: Condvar::new(),
+            all_finished: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            halt: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates the local queue for the new worker `id`.
+    ///
+    /// The worker counts as searching until it first runs out of work, so
+    /// jobs queued while its thread starts do not wake up other workers, or
+    /// make an autoscaling pool start even more workers.
+    pub(crate) fn register(&self, id: usize) -> Arc<LocalQueue> {
+        let local = Arc::new(LocalQueue {
+            id,
+            jobs: Mutex::new(VecDeque::new()),
+            retire: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            searching: AtomicBool::new(true),
+            metrics: WorkerMetrics::default(),
+        });
+        self.searching.fetch_add(1, Ordering::SeqCst);
+        self.locals.write().unwrap().push(Arc::clone(&local));
+        local
+    }
+
+    /// Removes the local queue of a stopping worker. Jobs that are still in
+    /// it are moved to the injector, and the other workers are woken up in
+    /// case the stopping worker swallowed a wakeup meant for them.
+    pub(crate) fn unregister(&self, local: &Arc<LocalQueue>) {
+        {
+            // The jobs are moved while holding `locals`, so `shutdown_now`
+            // finds them either in the local queue or in the injector.
+            let mut locals = self.locals.write().unwrap();
+            locals.retain(|other| !Arc::ptr_eq(other, local));
+            let leftover: Vec<Task> = local.jobs.lock().unwrap().drain(..).collect();
+            let mut injector = self.injector.lock().unwrap();
+            for task in leftover {
+                injector.push(task);
+            }
+            self.publish(&injector);
+        }
+        if local.searching.swap(false, Ordering::SeqCst) {
+            self.searching.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.notify_all();
+
+        let _sleep = self.sleep.lock().unwrap();
+        local.stopped.store(true, Ordering::SeqCst);
+        self.stopped.notify_all();
+    }
+
+    /// Marks the calling thread as the worker that owns `local`.
+    pub(crate) fn enter(&self, local: &Arc<LocalQueue>) {
+        let key = self as *const Scheduler as usize;
+        CURRENT.with(|current| *current.borrow_mut() = Some((key, Arc::clone(local))));
+    }
+
+    /// Returns the local queue of the calling thread, if it is one of this
+    /// scheduler's workers.
+    pub(crate) fn current_local(&self) -> Option<Arc<LocalQueue>> {
+        let key = self as *const Scheduler as usize;
+        CURRENT.with(|current| match &*current.borrow() {
+            Some((owner, local)) if *owner == key => Some(Arc::clone(local)),
+            _ => None,
+        })
+    }
+
+    /// Returns the index of the queue named `name`.
+    pub(crate) fn queue_index(&self, name: &str) -> Option<usize> {
+        self.queue_names.iter().position(|other| other == name)
+    }
+
+    /// Adds an unnamed queue to the injector and returns its index.
+    pub(crate) fn add_queue(&self, weight: u32) -> usize {
+        let mut injector = self.injector.lock().unwrap();
+        injector.queues.push(WeightedQueue::new(weight));
+        injector.queues.len() - 1
+    }
+
+    /// Changes the weight of the queue at `index`. Jobs already taken from
+    /// it are not charged again.
+    pub(crate) fn set_weight(&self, index: usize, weight: u32) {
+        let mut injector = self.injector.lock().unwrap();
+        injector.queues[index].stride = STRIDE / u128::from(weight);
+    }
+
+    /// Queues a job. Normal priority jobs of the default queue pushed by one
+    /// of this scheduler's workers go into that worker's local queue, all
+    /// others into the injector. `overflow` decides what happens when the
+    /// injector is full.
+    ///
+    /// Jobs from outside the pool are handed back once the scheduler is shut
+    /// down. Workers can still queue jobs, so running jobs can finish their
+    /// work, until `shutdown_now` is called. Jobs pushed by workers never
+    /// count towards the capacity of the injector.
+    pub(crate) fn push<F>(
+        &self,
+        mut task: Task<F>,
+        overflow: Overflow,
+    ) -> Result<(), TryExecuteError<Task<F>>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.timestamps || task.context.is_traced() {
+            task.queued_at = Some(Instant::now());
+        }
+        let from_worker = match self.current_local() {
+            Some(local) if task.priority == Priority::Normal && task.queue == DEFAULT_QUEUE => {
+                return self.push_local(&local, task);
+            }
+            local => local.is_some(),
+        };
+
+        let mut injector = self.injector.lock().unwrap();
+        let mut evicted = None;
+        loop {
+            if self.halt.load(Ordering::SeqCst) || (!from_worker && self.is_shut_down()) {
+                return Err(TryExecuteError::ShuttingDown(task));
+            }
+            if from_worker || !self.is_full(&injector) {
+                break;
+            }
+            match overflow {
+                Overflow::Block => injector = self.not_full.wait(injector).unwrap(),
+                Overflow::Force => break,
+                Overflow::Reject => return Err(TryExecuteError::Full(task)),
+                Overflow::DropOldest { block } => {
+                    evicted = injector.evict();
+                    if evicted.is_some() {
+                        self.queued.fetch_sub(1, Ordering::SeqCst);
+                        break;
+                    }
+                    if !block {
+                        return Err(TryExecuteError::Full(task));
+                    }
+                    injector = self.not_full.wait(injector).unwrap();
+                }
+            }
+        }
+        self.inject(injector, task.boxed());
+
+        // Dropping the job may run arbitrary code, so only do it after the
+        // injector has been unlocked.
+        if evicted.is_some() {
+            debug!("ThreadPool queue is full, dropped the oldest job.");
+            self.finished(1);
+        }
+        Ok(())
+    }
+
+    fn push_local<F>(
+        &self,
+        local: &LocalQueue,
+        task: Task<F>,
+    ) -> Result<(), TryExecuteError<Task<F>>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let jobs = local.jobs.lock().unwrap();
+        // Checked under the lock, so `shutdown_now` cannot miss the job.
+        if self.halt.load(Ordering::SeqCst) {
+            return Err(TryExecuteError::ShuttingDown(task));
+        }
+        self.push_locked(jobs, task.boxed());
+        Ok(())
+    }
+
+    /// Pushes `task` onto a local queue, which the caller has locked after
+    /// checking `halt`.
+    fn push_locked(&self, mut jobs: MutexGuard<'_, VecDeque<Task>>, task: Task) {
+        // Count the job before it becomes visible, so the count can never
+        // drop below zero when a worker takes the job right away.
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.unfinished.fetch_add(1, Ordering::SeqCst);
+        jobs.push_back(task);
+        drop(jobs);
+        self.notify_new_job();
+    }
+
+    /// Queues the job of `ThreadPool::join` on the local queue of the worker
+    /// that owns `local`, and returns the address of the job, which
+    /// `take_back` recognises it by. Returns `None` if the job cannot be
+    /// queued because of `shutdown_now`.
+    pub(crate) fn push_join(&self, local: &LocalQueue, task: Task) -> Option<*const ()> {
+        let jobs = local.jobs.lock().unwrap();
+        if self.halt.load(Ordering::SeqCst) {
+            return None;
+        }
+        let job = &*task.job as *const (dyn FnOnce() + Send) as *const ();
+        self.push_locked(jobs, task);
+        Some(job)
+    }
+
+    /// Takes the job at address `job` back out of the local queue of the
+    /// worker that owns `local`, unless another worker has stolen it. Returns
+    /// whether the job was taken back.
+    pub(crate) fn take_back(&self, local: &LocalQueue, job: *const ()) -> bool {
+        let mut jobs = local.jobs.lock().unwrap();
+        // Jobs queued after it have usually been taken already, so the job is
+        // at the back or close to it.
+        let task = match jobs.iter().rposition(|task| ptr::addr_eq(&*task.job, job)) {
+            Some(index) => jobs.remove(index),
+            None => return false,
+        };
+        drop(jobs);
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.finished(1);
+        drop(task);
+        true
+    }
+
+    /// Pushes `task` onto the injector, which the caller has locked.
+    fn inject(&self, mut injector: MutexGuard<'_, Injector>, task: Task) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.unfinished.fetch_add(1, Ordering::SeqCst);
+        injector.push(task);
+        self.publish(&injector);
+        drop(injector);
+        self.notify_new_job();
+    }
+
+    fn is_full(&self, injector: &Injector) -> bool {
+        self.capacity
+            .is_some_and(|capacity| injector.len >= capacity)
+    }
+
+    /// Updates the counts of injected jobs after the injector was changed.
+    /// Called while holding the injector lock, so the counts are never
+    /// stale for long.
+    fn publish(&self, injector: &Injector) {
+        self.injected.store(injector.len, Ordering::SeqCst);
+        self.urgent.store(injector.urgent, Ordering::SeqCst);
+    }
+
+    fn notify_new_job(&self) {
+        if self.searching.load(Ordering::SeqCst) == 0 {
+            self.notify_one();
+        }
+    }
+
+    /// Takes the next job for the worker that owns `local`.
+    pub(crate) fn find_job(&self, local: &Arc<LocalQueue>) -> Option<Task> {
+        if self.queued.load(Ordering::SeqCst) == 0 || self.halt.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        // Holding on to our own queue while stealing would deadlock with a
+        // worker stealing from us, so it is only locked in `pop_ready`.
+        let mut job = self.pop_ready(local);
+        if job.is_none() {
+            job = self.steal(local);
+        }
+        if job.is_none() {
+            job = self.pop_injected(Priority::Low);
+        }
+        if job.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.stop_searching(local);
+        }
+        job
+    }
+
+    /// Takes a high priority job from the injector, or else a job from the
+    /// local queue of `local`, or else a normal priority job from the
+    /// injector.
+    fn pop_ready(&self, local: &LocalQueue) -> Option<Task> {
+        // Without aging, the injector can only have a more urgent job than the
+        // local queue if it has high priority jobs.
+        let urgent = self.aging.is_some() || self.urgent.load(Ordering::SeqCst) > 0;
+        if !urgent || self.injected.load(Ordering::SeqCst) == 0 {
+            let task = local.jobs.lock().unwrap().pop_back();
+            return task.or_else(|| self.pop_injected(Priority::Normal));
+        }
+
+        let injector = self.injector.lock().unwrap();
+        let next = self.next_injected(&injector);
+        // Jobs in local queues always have normal priority.
+        if next.is_none_or(|(_, priority)| priority < Priority::High as usize) {
+            if let Some(task) = local.jobs.lock().unwrap().pop_back() {
+                return Some(task);
+            }
+        }
+        match next {
+            Some((lane, priority)) if priority >= Priority::Normal as usize => {
+                self.take_injected(injector, lane)
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the next job from the injector if its priority is at least
+    /// `min`.
+    fn pop_injected(&self, min: Priority) -> Option<Task> {
+        if self.injected.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let injector = self.injector.lock().unwrap();
+        match self.next_injected(&injector) {
+            Some((lane, priority)) if priority >= min as usize => {
+                self.take_injected(injector, lane)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the lane and the effective priority of the injected job
+    /// with the highest priority. Of jobs with the same priority, the one in
+    /// the queue with the lowest pass is next, and of jobs that aged to the
+    /// same priority in one queue, the oldest.
+    fn next_injected(&self, injector: &Injector) -> Option<(Lane, usize)> {
+        let aging = match self.aging {
+            Some(aging) => aging,
+            None => {
+                return (0..3).rev().find_map(|level| {
+                    injector
+                        .active
+                        .iter()
+                        .map(|&index| (index, &injector.queues[index]))
+                        .filter(|(_, queue)| !queue.levels[level].is_empty())
+                        .min_by_key(|(_, queue)| queue.pass)
+                        .map(|(index, _)| ((index, level), level))
+                });
+            }
+        };
+
+        let now = Instant::now();
+        injector
+            .active
+            .iter()
+            .flat_map(|&index| {
+                let queue = &injector.queues[index];
+                queue
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .map(move |(level, tasks)| ((index, level), queue.pass, tasks))
+            })
+            .filter_map(|(lane, pass, tasks)| {
+                let task = tasks.front()?;
+                let queued_at = task.queued_at.unwrap_or(now);
+                let waited = now.saturating_duration_since(queued_at);
+                let raised = waited
+                    .as_nanos()
+                    .checked_div(aging.as_nanos())
+                    .unwrap_or(u128::MAX);
+                let priority = (lane.1 as u128)
+                    .saturating_add(raised)
+                    .min(Priority::High as u128) as usize;
+                Some((lane, priority, pass, queued_at))
+            })
+            .max_by_key(|&(_, priority, pass, queued_at)| {
+                (priority, Reverse(pass), Reverse(queued_at))
+            })
+            .map(|(lane, priority, _, _)| (lane, priority))
+    }
+
+    fn take_injected(&self, mut injector: MutexGuard<'_, Injector>, lane: Lane) -> Option<Task> {
+        let task = injector.take(lane);
+        self.publish(&injector);
+        drop(injector);
+        if self.capacity.is_some() {
+            self.not_full.notify_one();
+        }
+        task
+    }
+
+    /// Steals the oldest job of another worker, starting with the worker
+    /// after `local` so that thieves spread out over their victims.
+    fn steal(&self, local: &Arc<LocalQueue>) -> Option<Task> {
+        let locals = self.locals.read().unwrap();
+        let start = locals
+            .iter()
+            .position(|other| Arc::ptr_eq(other, local))
+            .map_or(0, |index| index + 1);
+
+        (0..locals.len())
+            .map(|offset| &locals[(start + offset) % locals.len()])
+            .filter(|victim| !Arc::ptr_eq(victim, local))
+            .find_map(|victim| victim.jobs.lock().unwrap().pop_front())
+    }
+
+    /// Called when the worker that owns `local` found a job. If it was the
+    /// last searching worker and there is more work, another worker is woken
+    /// up to take over the search.
+    fn stop_searching(&self, local: &LocalQueue) {
+        if local.searching.swap(false, Ordering::SeqCst)
+            && self.searching.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.queued.load(Ordering::SeqCst) > 0
+        {
+            self.notify_one();
+        }
+    }
+
+    /// Blocks the worker that owns `local` until there might be work for it,
+    /// until it has to stop, or until `deadline` has passed. The worker is
+    /// searching when this returns. Returns `false` if the deadline passed.
+    pub(crate) fn wait_for_work(&self, local: &LocalQueue, deadline: Option<Instant>) -> bool {
+        let mut claimed = self.sleep.lock().unwrap();
+        if local.searching.load(Ordering::SeqCst) {
+            self.searching.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        let woken = loop {
+            // A claimed wakeup already took this worker out of `idle` and
+            // counted it as searching.
+            if *claimed > 0 {
+                *claimed -= 1;
+                break true;
+            }
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let timed_out = timeout == Some(Duration::ZERO);
+            if timed_out
+                || self.queued.load(Ordering::SeqCst) > 0
+                || local.is_retiring()
+                || self.is_shut_down()
+            {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                self.searching.fetch_add(1, Ordering::SeqCst);
+                break !timed_out;
+            }
+            claimed = match timeout {
+                Some(timeout) => self.wake.wait_timeout(claimed, timeout).unwrap().0,
+                None => self.wake.wait(claimed).unwrap(),
+            };
+        };
+        local.searching.store(true, Ordering::SeqCst);
+        woken
+    }
+
+    /// Returns whether jobs are queued while no worker is waiting for work
+    /// or about to look for it.
+    pub(crate) fn is_backed_up(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) > 0
+            && self.idle.load(Ordering::SeqCst) == 0
+            && self.searching.load(Ordering::SeqCst) == 0
+    }
+
+    /// Tells the worker that owns `local` to stop after its current job.
+    pub(crate) fn retire(&self, local: &LocalQueue) {
+        local.retire.store(true, Ordering::SeqCst);
+        self.notify_all();
+    }
+
+    /// Tells all workers to stop once there are no more queued jobs, and
+    /// wakes up submitters that are blocked on a full injector.
+    pub(crate) fn shutdown(&self) {
+        {
+            let _injector = self.injector.lock().unwrap();
+            self.shutdown.store(true, Ordering::SeqCst);
+            self.not_full.notify_all();
+        }
+        self.notify_all();
+    }
+
+    /// Tells all workers to stop after their current job, and takes all
+    /// queued jobs out of the scheduler. Nothing can be queued afterwards.
+    pub(crate) fn shutdown_now(&self) -> Vec<Task> {
+        // Holding `locals` keeps stopping workers from moving their jobs
+        // between the queues while they are drained.
+        let locals = self.locals.read().unwrap();
+        let mut tasks: Vec<Task> = {
+            let mut injector = self.injector.lock().unwrap();
+            self.shutdown.store(true, Ordering::SeqCst);
+            self.halt.store(true, Ordering::SeqCst);
+            self.not_full.notify_all();
+            let tasks = injector.drain();
+            self.publish(&injector);
+            tasks
+        };
+        for local in locals.iter() {
+            tasks.extend(local.jobs.lock().unwrap().drain(..));
+        }
+        drop(locals);
+
+        self.queued.fetch_sub(tasks.len(), Ordering::SeqCst);
+        self.finished(tasks.len());
+        self.notify_all();
+        tasks
+    }
+
+    /// Takes the job with the cancellation token `token` out of the queues.
+    /// Returns `None` if it is not queued, for example because a worker has
+    /// taken it already.
+    pub(crate) fn remove(&self, token: &CancellationToken) -> Option<Task> {
+        let is_job = |task: &Task| task.token.as_ref().is_some_and(|other| other.ptr_eq(token));
+        let injected = {
+            let mut injector = self.injector.lock().unwrap();
+            let task = injector
+                .find(is_job)
+                .and_then(|(lane, position)| injector.remove(lane, position));
+            if task.is_some() {
+                self.publish(&injector);
+                if self.capacity.is_some() {
+                    self.not_full.notify_one();
+                }
+            }
+            task
+        };
+        let task = injected.or_else(|| {
+            let locals = self.locals.read().unwrap();
+            locals.iter().find_map(|local| {
+                let mut jobs = local.jobs.lock().unwrap();
+                let position = jobs.iter().position(is_job)?;
+                jobs.remove(position)
+            })
+        })?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.finished(1);
+        Some(task)
+    }
+
+    /// Called when `count` jobs have finished running, or were taken out of
+    /// the queues without running.
+    pub(crate) fn finished(&self, count: usize) {
+        if count > 0 && self.unfinished.fetch_sub(count, Ordering::SeqCst) == count {
+            let _sleep = self.sleep.lock().unwrap();
+            self.all_finished.notify_all();
+        }
+    }
+
+    /// Blocks until there are no queued or running jobs, or until `deadline`
+    /// has passed. Returns whether all jobs finished.
+    pub(crate) fn wait_all_finished(&self, deadline: Option<Instant>) -> bool {
+        let mut sleep = self.sleep.lock().unwrap();
+        while self.unfinished.load(Ordering::SeqCst) > 0 {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    sleep = self
+                        .all_finished
+                        .wait_timeout(sleep, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+                None => sleep = self.all_finished.wait(sleep).unwrap(),
+            }
+        }
+        true
+    }
+
+    /// Blocks until the worker that owns `local` has stopped, or until
+    /// `deadline` has passed. Returns whether the worker stopped.
+    pub(crate) fn wait_stopped(&self, local: &LocalQueue, deadline: Instant) -> bool {
+        let mut sleep = self.sleep.lock().unwrap();
+        while !local.stopped.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            sleep = self.stopped.wait_timeout(sleep, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Returns the number of jobs in the injector and all local queues.
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Wakes up one idle worker, if there is one.
+    fn notify_one(&self) {
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            let mut claimed = self.sleep.lock().unwrap();
+            if self.idle.load(Ordering::SeqCst) > 0 {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                self.searching.fetch_add(1, Ordering::SeqCst);
+                *claimed += 1;
+                self.wake.notify_one();
+            }
+        }
+    }
+
+    /// Wakes up all idle workers.
+    fn notify_all(&self) {
+        let mut claimed = self.sleep.lock().unwrap();
+        let idle = self.idle.swap(0, Ordering::SeqCst);
+        self.searching.fetch_add(idle, Ordering::SeqCst);
+        *claimed += idle;
+        self.wake.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Priority;
+    use crate::tests::occupy;
+    use crate::{ThreadPool, ThreadPoolBuilder, ThreadPoolError};
+
+    /// Queues a job on `pool` that appends `id` to `log`.
+    fn log_with_priority(
+        pool: &ThreadPool,
+        log: &Arc<Mutex<Vec<usize>>>,
+        priority: Priority,
+        id: usize,
+    ) {
+        let log = Arc::clone(log);
+        pool.execute_with_priority(priority, move || log.lock().unwrap().push(id));
+    }
+
+    #[test]
+    fn higher_priorities_run_first() {
+        let pool = ThreadPool::new(1);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let release = occupy(&pool);
+        let priorities = [
+            Priority::Low,
+            Priority::Normal,
+            Priority::High,
+            Priority::Low,
+            Priority::High,
+        ];
+        for (id, &priority) in priorities.iter().enumerate() {
+            log_with_priority(&pool, &log, priority, id);
+        }
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(*log.lock().unwrap(), [2, 4, 1, 0, 3]);
+    }
+
+    #[test]
+    fn aging_raises_waiting_jobs() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .priority_aging(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let release = occupy(&pool);
+        log_with_priority(&pool, &log, Priority::Low, 0);
+        // Long enough for the low priority job to age to the top.
+        thread::sleep(Duration::from_millis(30));
+        log_with_priority(&pool, &log, Priority::High, 1);
+        log_with_priority(&pool, &log, Priority::Normal, 2);
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(*log.lock().unwrap(), [0, 1, 2]);
+    }
+
+    /// Queues `count` jobs in `queue` of `pool` that append `c` to `log`.
+    fn log_in(pool: &ThreadPool, log: &Arc<Mutex<String>>, queue: &str, c: char, count: usize) {
+        for _ in 0..count {
+            let log = Arc::clone(log);
+            pool.execute_in(queue, move || log.lock().unwrap().push(c));
+        }
+    }
+
+    #[test]
+    fn queues_share_the_workers_by_weight() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue("io", 3)
+            .queue("background", 1)
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(String::new()));
+        let release = occupy(&pool);
+        log_in(&pool, &log, "background", 'b', 20);
+        log_in(&pool, &log, "io", 'i', 20);
+        drop(release);
+        pool.wait_idle();
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 40);
+        // While both queues have jobs, every stretch of the order is split
+        // three to one.
+        for n in 1..=24 {
+            let background = log[..n].matches('b').count();
+            assert!(background.abs_diff(n / 4) <= 1, "{}", log);
+        }
+    }
+
+    #[test]
+    fn empty_queues_do_not_save_up_turns() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue("a", 1)
+            .queue("b", 1)
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(String::new()));
+        log_in(&pool, &log, "b", 'b', 10);
+        pool.wait_idle();
+
+        log.lock().unwrap().clear();
+        let release = occupy(&pool);
+        log_in(&pool, &log, "a", 'a', 10);
+        log_in(&pool, &log, "b", 'b', 10);
+        drop(release);
+        pool.wait_idle();
+        let log = log.lock().unwrap();
+        assert!(log[..6].matches('b').count() >= 2, "{}", log);
+    }
+
+    #[test]
+    fn priorities_come_before_weights() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue("bulk", 10)
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(String::new()));
+        let release = occupy(&pool);
+        log_in(&pool, &log, "bulk", 'k', 5);
+        let urgent = Arc::clone(&log);
+        pool.execute_with_priority(Priority::High, move || urgent.lock().unwrap().push('H'));
+        drop(release);
+        pool.wait_idle();
+        assert!(log.lock().unwrap().starts_with('H'));
+    }
+
+    #[test]
+    fn queues_are_checked() {
+        assert!(matches!(
+            ThreadPoolBuilder::new().queue("io", 0).build(),
+            Err(ThreadPoolError::ZeroWeight { queue }) if queue == "io"
+        ));
+        let pool = ThreadPool::new(1);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.execute_in("io", || ())));
+        assert!(result.is_err());
+        assert_eq!(pool.submit_in("default", || 1).join().unwrap(), 1);
+    }
+}
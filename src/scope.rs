@@ -0,0 +1,232 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::metrics::Outcome;
+use crate::scheduler::{Overflow, Task};
+use crate::{worker, Job, ThreadPool};
+
+/// A scope for jobs that borrow from the stack, see `ThreadPool::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    /// The number of spawned jobs that have not finished yet.
+    pending: Mutex<usize>,
+    done: Condvar,
+    /// The payload of the first job that panicked.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// Marks a scoped job as finished when dropped, whether it ran or not. A job
+/// that is dropped without running, because the pool shut down or the queue
+/// was full, is reported like a panic.
+struct Pending {
+    state: Arc<ScopeState>,
+    ran: bool,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.ran {
+            let payload: Box<dyn Any + Send> = Box::new("scoped job was dropped before it ran");
+            self.state.panic.lock().unwrap().get_or_insert(payload);
+        }
+        let mut pending = self.state.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Creates a scope in which jobs can be spawned that borrow from the
+    /// caller's stack.
+    ///
+    /// All jobs spawned in the scope have finished when this returns. If any
+    /// of them panicked, the first panic is resumed here after all jobs have
+    /// finished. A job that was dropped without running, for example by
+    /// `shutdown_now`, counts as a panic.
+    ///
+    /// When called from one of this pool's workers, the worker runs queued
+    /// jobs while it waits.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // The jobs may borrow what `f` borrows, so they have to finish even
+        // when `f` panics.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+
+        if let Some(payload) = scope.state.panic.lock().unwrap().take() {
+            panic::resume_unwind(payload);
+        }
+        match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawns a job on the pool that may borrow anything that outlives the
+    /// scope.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+
+        let state = Arc::clone(&self.state);
+        // A tuple drops its fields in order, so `f` is gone before the job is
+        // marked as finished even if the job is dropped without running.
+        let pending = Pending {
+            state: Arc::clone(&self.state),
+            ran: false,
+        };
+        let job = (f, pending);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let (f, mut pending) = job;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                worker::report(Outcome::Panicked);
+                state.panic.lock().unwrap().get_or_insert(payload);
+            }
+            pending.ran = true;
+            drop(pending);
+        });
+        // SAFETY: `ThreadPool::scope` does not return before every `Pending`
+        // of this scope has been dropped, and the job does not touch `f`
+        // after dropping it. So nothing borrowed for `'scope` is used after
+        // the scope ends.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        let task = Task {
+            scoped: true,
+            ..Task::new(job)
+        };
+        if let Err(err) = self.pool.shared.push(task, Overflow::Block) {
+            panic!("failed to spawn scoped job: {}", err);
+        }
+    }
+
+    /// Blocks until all jobs spawned in the scope have finished.
+    fn wait(&self) {
+        let mut pending = self.state.pending.lock().unwrap();
+        while *pending > 0 {
+            drop(pending);
+            let helped = worker::help(&self.pool.shared);
+            pending = self.state.pending.lock().unwrap();
+            if !helped && *pending > 0 {
+                pending = self.state.done.wait(pending).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use crate::handle::panic_message;
+    use crate::ThreadPool;
+
+    #[test]
+    fn jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let mut results = vec![0; 16];
+        pool.scope(|s| {
+            for (i, result) in results.iter_mut().enumerate() {
+                s.spawn(move || *result = i * 2);
+            }
+        });
+        assert_eq!(results, (0..16).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scope_on_a_worker_runs_its_own_jobs() {
+        let pool = ThreadPool::new(1);
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            s.spawn(|| {
+                // The only worker waits here, so it has to run these itself.
+                pool.scope(|s| {
+                    for _ in 0..4 {
+                        s.spawn(|| {
+                            count.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                });
+            });
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn panic_is_resumed_after_all_jobs_finished() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("scoped job panicked"));
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(panic_message(&*payload), "scoped job panicked");
+        assert_eq!(finished.load(Ordering::SeqCst), 8);
+        // The pool is still usable.
+        assert_eq!(pool.submit(|| 1).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn shutdown_now_drops_queued_scoped_jobs() {
+        let pool = ThreadPool::new(1);
+        let ran = AtomicBool::new(false);
+        let (started_tx, started_rx) = mpsc::channel();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| {
+                    started_tx.send(()).unwrap();
+                    // Keep the only worker busy until the queue is drained.
+                    while !pool.shared.scheduler.is_shut_down() {
+                        thread::yield_now();
+                    }
+                });
+                s.spawn(|| ran.store(true, Ordering::SeqCst));
+                started_rx.recv().unwrap();
+                assert!(pool.shutdown_now().is_empty());
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(
+            panic_message(&*payload),
+            "scoped job was dropped before it ran"
+        );
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::metrics::{Outcome, TenantStats};
+use crate::scheduler::{Overflow, Scheduler, Task};
+use crate::{with_handle, worker, JobHandle, Shared, ThreadPool};
+
+/// A party that jobs are submitted for, see `ThreadPool::execute_for`.
+///
+/// Each tenant has its own queue in the injector, which shares the workers
+/// with the other queues by weight. Jobs beyond the tenant's limit are held
+/// back here, outside the scheduler, until one of its jobs has finished.
+pub(crate) struct Tenant {
+    name: String,
+    /// The index of the tenant's queue in the injector.
+    queue: usize,
+    state: Mutex<TenantState>,
+    running: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    cancelled: AtomicU64,
+}
+
+struct TenantState {
+    weight: u32,
+    limit: Option<usize>,
+    /// The number of jobs that are queued in the scheduler or running.
+    admitted: usize,
+    /// The jobs held back by the limit, oldest first.
+    pending: VecDeque<Task>,
+}
+
+impl TenantState {
+    fn is_full(&self) -> bool {
+        self.limit.is_some_and(|limit| self.admitted >= limit)
+    }
+}
+
+impl Tenant {
+    /// Queues held back jobs for as long as the tenant is below its limit.
+    fn admit_pending(self: &Arc<Tenant>, shared: &Arc<Shared>) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if state.is_full() {
+                return;
+            }
+            let task = match state.pending.pop_front() {
+                Some(task) => task,
+                None => return,
+            };
+            state.admitted += 1;
+            drop(state);
+
+            let task = admitted(self, shared, task);
+            if let Err(err) = shared.push(task, Overflow::Force) {
+                // The pool is shutting down, so none of the held back jobs
+                // can run. They are dropped before the rejected job, which
+                // would otherwise try to admit them one by one.
+                let pending = mem::take(&mut self.state.lock().unwrap().pending);
+                drop(pending);
+                drop(err);
+                return;
+            }
+        }
+    }
+
+    fn record(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Completed => &self.completed,
+            Outcome::Panicked => &self.panicked,
+            Outcome::Cancelled => &self.cancelled,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TenantStats {
+        let state = self.state.lock().unwrap();
+        let running = self.running.load(Ordering::Relaxed);
+        TenantStats {
+            name: self.name.clone(),
+            weight: state.weight,
+            limit: state.limit,
+            queued: state.admitted.saturating_sub(running) + state.pending.len(),
+            running,
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts a job of `tenant` as admitted until it is dropped, whether the job
+/// ran or not, and then admits the tenant's next held back job.
+struct Admission {
+    tenant: Arc<Tenant>,
+    shared: Weak<Shared>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.tenant.state.lock().unwrap().admitted -= 1;
+        match self.shared.upgrade() {
+            Some(shared) => self.tenant.admit_pending(&shared),
+            None => drop(mem::take(&mut self.tenant.state.lock().unwrap().pending)),
+        }
+    }
+}
+
+/// Wraps the job of `task`, which was counted as admitted, so that it counts
+/// as running for `tenant` and records its outcome.
+fn admitted<F>(
+    tenant: &Arc<Tenant>,
+    shared: &Arc<Shared>,
+    task: Task<F>,
+) -> Task<impl FnOnce() + Send + 'static>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let admission = Admission {
+        tenant: Arc::clone(tenant),
+        shared: Arc::downgrade(shared),
+    };
+    task.map(|job| {
+        move || {
+            let tenant = &admission.tenant;
+            tenant.running.fetch_add(1, Ordering::Relaxed);
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            tenant.running.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(()) => tenant.record(worker::outcome()),
+                Err(payload) => {
+                    tenant.record(Outcome::Panicked);
+                    panic::resume_unwind(payload);
+                }
+            }
+        }
+    })
+}
+
+/// The tenants of a pool, in the order they first submitted a job.
+#[derive(Default)]
+pub(crate) struct Tenants {
+    inner: Mutex<TenantMap>,
+}
+
+#[derive(Default)]
+struct TenantMap {
+    by_name: HashMap<String, usize>,
+    list: Vec<Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Returns the tenant named `name`, and creates it with a weight of 1 and
+    /// no limit if it does not exist yet.
+    fn get(&self, name: &str, scheduler: &Scheduler) -> Arc<Tenant> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&index) = inner.by_name.get(name) {
+            return Arc::clone(&inner.list[index]);
+        }
+        let tenant = Arc::new(Tenant {
+            name: name.to_string(),
+            queue: scheduler.add_queue(1),
+            state: Mutex::new(TenantState {
+                weight: 1,
+                limit: None,
+                admitted: 0,
+                pending: VecDeque::new(),
+            }),
+            running: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+        });
+        let index = inner.list.len();
+        inner.list.push(Arc::clone(&tenant));
+        inner.by_name.insert(name.to_string(), index);
+        tenant
+    }
+
+    /// Takes the held back jobs of all tenants, see `ThreadPool::shutdown_now`.
+    pub(crate) fn drain_pending(&self) -> Vec<Task> {
+        let tenants = self.inner.lock().unwrap().list.clone();
+        tenants
+            .iter()
+            .flat_map(|tenant| mem::take(&mut tenant.state.lock().unwrap().pending))
+            .collect()
+    }
+
+    /// Counts a job that was taken out of `queue` because it was cancelled,
+    /// if `queue` belongs to a tenant.
+    pub(crate) fn record_cancelled(&self, queue: usize) {
+        let inner = self.inner.lock().unwrap();
+        if let Some(tenant) = inner.list.iter().find(|tenant| tenant.queue == queue) {
+            tenant.record(Outcome::Cancelled);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<TenantStats> {
+        let tenants = self.inner.lock().unwrap().list.clone();
+        tenants.iter().map(|tenant| tenant.stats()).collect()
+    }
+}
+
+impl ThreadPool {
+    /// Like `execute`, but on behalf of `tenant`, such as a customer of a
+    /// multi-tenant service. A tenant is created when it first submits a
+    /// job, with a weight of 1 and no limit.
+    ///
+    /// Every tenant has a queue of its own. While several tenants have jobs
+    /// waiting, workers take them in turns, or in proportion to the tenants'
+    /// weights, so a tenant that submits many jobs cannot hold up the
+    /// others. Tenant queues share the workers with the pool's other queues
+    /// in the same way, see `ThreadPoolBuilder::queue`.
+    ///
+    /// `ThreadPool::stats` breaks the pool's jobs down by tenant. Tenants are
+    /// kept for as long as the pool exists.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn execute_for<F>(&self, tenant: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_task_for(tenant, Task::new(f));
+    }
+
+    /// Queues `task` on behalf of `tenant` like `execute_for` does.
+    fn execute_task_for<F>(&self, tenant: &str, task: Task<F>)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let tenant = self.shared.tenants.get(tenant, &self.shared.scheduler);
+        let task = Task {
+            queue: tenant.queue,
+            ..task
+        };
+
+        let mut state = tenant.state.lock().unwrap();
+        if state.is_full() {
+            // Held back jobs are queued by workers, which can queue jobs
+            // until the pool has stopped, so check what `execute` would.
+            let scheduler = &self.shared.scheduler;
+            if scheduler.is_shut_down() && scheduler.current_local().is_none() {
+                panic!("failed to execute job: thread pool is shutting down");
+            }
+            state.pending.push_back(task.boxed());
+            return;
+        }
+        state.admitted += 1;
+        drop(state);
+
+        self.execute_task(admitted(&tenant, &self.shared, task));
+    }
+
+    /// Like `submit`, but on behalf of `tenant`, see `execute_for`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`.
+    pub fn submit_for<F, T>(&self, tenant: &str, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (task, handle) = with_handle(&self.shared, move |_| f());
+        self.execute_task_for(tenant, task);
+        handle
+    }
+
+    /// Sets the weight of `tenant`, creating the tenant if needed. A tenant
+    /// with weight 2 gets twice as many jobs run as a tenant with weight 1
+    /// while both have jobs waiting. The default is 1.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `weight` is zero.
+    pub fn set_tenant_weight(&self, tenant: &str, weight: u32) {
+        assert!(weight > 0, "tenant weight must be greater than zero");
+        let tenant = self.shared.tenants.get(tenant, &self.shared.scheduler);
+        let mut state = tenant.state.lock().unwrap();
+        state.weight = weight;
+        self.shared.scheduler.set_weight(tenant.queue, weight);
+    }
+
+    /// Limits how many jobs of `tenant` can be queued on the workers or
+    /// running at the same time, creating the tenant if needed. Further jobs
+    /// are held back until one of the tenant's jobs has finished, and do not
+    /// count towards the queue capacity. `None`, the default, removes the
+    /// limit.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `limit` is zero.
+    pub fn set_tenant_limit(&self, tenant: &str, limit: Option<usize>) {
+        assert!(limit != Some(0), "tenant limit must be greater than zero");
+        let tenant = self.shared.tenants.get(tenant, &self.shared.scheduler);
+        tenant.state.lock().unwrap().limit = limit;
+        tenant.admit_pending(&self.shared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    use crate::tests::occupy;
+    use crate::ThreadPool;
+
+    /// Queues `count` jobs for `tenant` on `pool` that append `c` to `log`.
+    fn log_for(pool: &ThreadPool, log: &Arc<Mutex<String>>, tenant: &str, c: char, count: usize) {
+        for _ in 0..count {
+            let log = Arc::clone(log);
+            pool.execute_for(tenant, move || log.lock().unwrap().push(c));
+        }
+    }
+
+    #[test]
+    fn tenants_take_turns() {
+        let pool = ThreadPool::new(1);
+        let log = Arc::new(Mutex::new(String::new()));
+        let release = occupy(&pool);
+        log_for(&pool, &log, "noisy", 'n', 30);
+        log_for(&pool, &log, "quiet", 'q', 5);
+        drop(release);
+        pool.wait_idle();
+        assert!(log.lock().unwrap().starts_with("nqnqnqnqnq"));
+    }
+
+    #[test]
+    fn weights_divide_the_turns() {
+        let pool = ThreadPool::new(1);
+        pool.set_tenant_weight("gold", 3);
+        let log = Arc::new(Mutex::new(String::new()));
+        let release = occupy(&pool);
+        log_for(&pool, &log, "basic", 'b', 12);
+        log_for(&pool, &log, "gold", 'g', 12);
+        drop(release);
+        pool.wait_idle();
+        let log = log.lock().unwrap();
+        assert_eq!(log[..12].matches('g').count(), 9, "{}", log);
+        let gold = pool.stats().tenants.into_iter().find(|t| t.name == "gold");
+        assert_eq!(gold.map(|t| (t.weight, t.completed)), Some((3, 12)));
+    }
+
+    #[test]
+    fn limit_holds_jobs_back() {
+        let pool = ThreadPool::new(4);
+        pool.set_tenant_limit("capped", Some(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let handles: Vec<_> = (0..6)
+            .map(|i| {
+                let running = Arc::clone(&running);
+                let most = Arc::clone(&most);
+                let started_tx = started_tx.clone();
+                let release_rx = Arc::clone(&release_rx);
+                pool.submit_for("capped", move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    let _ = started_tx.send(());
+                    let _ = release_rx.lock().unwrap().recv();
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    i
+                })
+            })
+            .collect();
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
+
+        let stats = pool.stats();
+        let capped = stats.tenants.iter().find(|t| t.name == "capped").unwrap();
+        assert_eq!(capped.limit, Some(2));
+        assert_eq!((capped.queued, capped.running), (4, 2));
+        // Held back jobs do not hold up other tenants.
+        assert_eq!(pool.submit_for("other", || 1).join().unwrap(), 1);
+
+        drop(release_tx);
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shutdown_now_returns_held_back_jobs() {
+        let pool = ThreadPool::new(1);
+        pool.set_tenant_limit("capped", Some(1));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute_for("capped", move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let ran = Arc::clone(&ran);
+            pool.execute_for("capped", move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let jobs = thread::scope(|s| {
+            let shutdown = s.spawn(|| pool.shutdown_now());
+            // Release the running job once the held back jobs have been
+            // taken out, so it cannot queue the next one.
+            while pool.stats().tenants[0].queued > 0 {
+                thread::yield_now();
+            }
+            drop(release_tx);
+            shutdown.join().unwrap()
+        });
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,438 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::deadline::{Deadlines, Key};
+use crate::metrics::Outcome;
+use crate::scheduler::{Overflow, Task};
+use crate::trace::Context;
+use crate::{worker, CancellationToken, Job, Shared, ThreadPool, ThreadPoolError};
+
+/// How a periodic job is rescheduled, see `ThreadPool::execute_periodic`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Start a run every period, measured from the start of one run to the
+    /// start of the next. `MissedTicks` decides what happens when a run
+    /// could not start on time.
+    FixedRate(Duration, MissedTicks),
+    /// Wait for the period after a run has finished before starting the
+    /// next one.
+    FixedDelay(Duration),
+}
+
+impl Schedule {
+    fn period(&self) -> Duration {
+        match *self {
+            Schedule::FixedRate(period, _) | Schedule::FixedDelay(period) => period,
+        }
+    }
+
+    /// Returns when to run next, after the run that was due at `due` has
+    /// finished.
+    fn next_due(&self, due: Instant) -> Instant {
+        let now = Instant::now();
+        let (period, missed) = match *self {
+            Schedule::FixedRate(period, missed) => (period, missed),
+            Schedule::FixedDelay(delay) => return now + delay,
+        };
+
+        let next = due + period;
+        if next > now {
+            return next;
+        }
+        match missed {
+            MissedTicks::Burst => next,
+            MissedTicks::Skip => {
+                // The first tick of the original schedule that is still ahead.
+                let late = (now - next).as_nanos() % period.as_nanos();
+                now + period - Duration::from_nanos(late as u64)
+            }
+            MissedTicks::Delay => now + period,
+        }
+    }
+}
+
+/// What a fixed rate job does about runs that could not start on time,
+/// because the previous run took too long or the pool was busy. Runs never
+/// overlap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Run the missed runs right away, one after the other, until the job
+    /// has caught up with its schedule.
+    Burst,
+    /// Drop the missed runs and continue at the next tick of the original
+    /// schedule.
+    Skip,
+    /// Run once right away, and shift the schedule so that the following
+    /// runs are one period apart from then on.
+    Delay,
+}
+
+/// A handle to a job scheduled with `ThreadPool::execute_after`,
+/// `ThreadPool::execute_at` or one of the periodic variants.
+///
+/// Dropping the handle does not cancel the job.
+pub struct TimerHandle {
+    scheduled: Arc<Scheduled>,
+    shared: Weak<Shared>,
+}
+
+impl TimerHandle {
+    /// Cancels the job. A delayed job that has not started yet does not run,
+    /// a periodic job does not run again. The job is dropped right away
+    /// unless it is running or already queued. A run that has already
+    /// started is not interrupted.
+    pub fn cancel(&self) {
+        self.scheduled.token.cancel();
+        let key = self.scheduled.key.lock().unwrap().take();
+        let (key, shared) = match (key, self.shared.upgrade()) {
+            (Some(key), Some(shared)) => (key, shared),
+            _ => return,
+        };
+        // `None` if the timer thread has taken the entry already, and then
+        // it counts the job as cancelled.
+        if let Some(entry) = shared.timer.remove(key) {
+            shared.metrics.record(Outcome::Cancelled);
+            drop(entry);
+        }
+    }
+
+    /// Returns whether `cancel` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.scheduled.token.is_cancelled()
+    }
+}
+
+/// The state of a scheduled job that is shared with its `TimerHandle`.
+#[derive(Default)]
+struct Scheduled {
+    token: CancellationToken,
+    /// The key of the job's entry in the timer, while it has one. A periodic
+    /// job gets a new entry for every run.
+    key: Mutex<Option<Key>>,
+}
+
+/// Keeps jobs that are not due yet, and moves them to the scheduler once
+/// they are. The thread that does this is only started when the first job
+/// is scheduled.
+pub(crate) type Timer = Deadlines<Entry>;
+
+pub(crate) struct Entry {
+    scheduled: Arc<Scheduled>,
+    /// Where the job was scheduled from, rather than the timer thread.
+    context: Context,
+    job: Job,
+}
+
+/// Queues `job` on the pool at `due`, unless it is cancelled by then.
+fn schedule(
+    shared: &Arc<Shared>,
+    due: Instant,
+    scheduled: &Arc<Scheduled>,
+    context: Context,
+    job: Job,
+) -> Result<(), ThreadPoolError> {
+    shared.timer.start(shared, |shared| &shared.timer, queue)?;
+    let mut key = scheduled.key.lock().unwrap();
+    // Checked under the lock, so `TimerHandle::cancel` either finds the key
+    // of the new entry, or the entry is not added.
+    if scheduled.token.is_cancelled() {
+        shared.metrics.record(Outcome::Cancelled);
+        return Ok(());
+    }
+    let entry = Entry {
+        scheduled: Arc::clone(scheduled),
+        context,
+        job,
+    };
+    match shared.timer.insert(due, entry) {
+        Some(new_key) => {
+            *key = Some(new_key);
+            Ok(())
+        }
+        None => Err(ThreadPoolError::ShuttingDown),
+    }
+}
+
+/// Queues the job of an entry that is due.
+fn queue(shared: &Arc<Shared>, entry: Entry) {
+    if entry.scheduled.token.is_cancelled() {
+        shared.metrics.record(Outcome::Cancelled);
+    } else {
+        // Waiting for room would hold up every other entry, so the job is
+        // queued even if the queue is full. This fails only when the pool is
+        // shutting down, which stops the timer as well.
+        let task = Task {
+            context: entry.context,
+            ..Task::new(entry.job)
+        };
+        let _ = shared.push(task, Overflow::Force);
+    }
+}
+
+/// Schedules the run of a periodic job that is due at `due`. After the run,
+/// the job schedules its next run. Every run gets the `context` of the
+/// original caller.
+fn schedule_periodic(
+    shared: &Arc<Shared>,
+    due: Instant,
+    schedule: Schedule,
+    scheduled: Arc<Scheduled>,
+    context: Context,
+    f: Arc<dyn Fn() + Send + Sync + 'static>,
+) -> Result<(), ThreadPoolError> {
+    let job: Job = {
+        let shared = Arc::clone(shared);
+        let scheduled = Arc::clone(&scheduled);
+        let context = context.clone();
+        Box::new(move || {
+            if scheduled.token.is_cancelled() {
+                worker::report(Outcome::Cancelled);
+                return;
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f()));
+            let next = schedule.next_due(due);
+            // Fails only when the pool is shutting down.
+            let _ = schedule_periodic(&shared, next, schedule, scheduled, context, f);
+            // A panic is reported like that of any other job, and does not
+            // stop the schedule.
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+        })
+    };
+    self::schedule(shared, due, &scheduled, context, job)
+}
+
+impl ThreadPool {
+    /// Executes `f` on the pool once `delay` has passed. See `execute_at`.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_at(Instant::now() + delay, f)
+    }
+
+    /// Executes `f` on the pool at `deadline`, or right away if `deadline`
+    /// has passed. `f` is queued like a job passed to `execute` once it is
+    /// due, so it may start later if the pool is busy.
+    ///
+    /// The overflow policy does not apply to `f`: it is queued when due even
+    /// if the queue is full, so that a full queue does not hold up other
+    /// delayed and periodic jobs. The same holds for periodic jobs.
+    ///
+    /// Jobs that are not due yet are dropped when the pool shuts down.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the pool is shutting down, or if the timer thread
+    /// cannot be spawned.
+    pub fn execute_at<F>(&self, deadline: Instant, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let scheduled = Arc::new(Scheduled::default());
+        let job: Job = {
+            let scheduled = Arc::clone(&scheduled);
+            Box::new(move || {
+                if scheduled.token.is_cancelled() {
+                    worker::report(Outcome::Cancelled);
+                } else {
+                    f();
+                }
+            })
+        };
+        let context = Context::current();
+        if let Err(err) = schedule(&self.shared, deadline, &scheduled, context, job) {
+            panic!("failed to schedule job: {}", err);
+        }
+        TimerHandle {
+            scheduled,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// Executes `f` on the pool every `period`, starting one period from
+    /// now. This is `execute_periodic` with
+    /// `Schedule::FixedRate(period, MissedTicks::Skip)`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute_periodic`.
+    pub fn execute_every<F>(&self, period: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.execute_periodic(Schedule::FixedRate(period, MissedTicks::Skip), f)
+    }
+
+    /// Executes `f` on the pool repeatedly according to `schedule`, until
+    /// the returned handle is cancelled or the pool shuts down. The first run
+    /// starts one period from now.
+    ///
+    /// Runs never overlap, the next run is scheduled when the previous one
+    /// has finished. A panic in `f` is passed to the panic handler and does
+    /// not stop the schedule.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the period is zero, if the pool is shutting down,
+    /// or if the timer thread cannot be spawned.
+    pub fn execute_periodic<F>(&self, schedule: Schedule, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let period = schedule.period();
+        assert!(period > Duration::ZERO, "period must not be zero");
+
+        let scheduled = Arc::new(Scheduled::default());
+        let due = Instant::now() + period;
+        let context = Context::current();
+        let f = Arc::new(f);
+        let first = Arc::clone(&scheduled);
+        if let Err(err) = schedule_periodic(&self.shared, due, schedule, first, context, f) {
+            panic!("failed to schedule job: {}", err);
+        }
+        TimerHandle {
+            scheduled,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{MissedTicks, Schedule};
+    use crate::tests::occupy;
+    use crate::{OverflowPolicy, ThreadPool, ThreadPoolBuilder};
+
+    #[test]
+    fn cancel_drops_a_delayed_job_right_away() {
+        let pool = ThreadPool::new(1);
+        let witness = Arc::new(());
+        let held = Arc::clone(&witness);
+        let handle = pool.execute_after(Duration::from_secs(3600), move || drop(held));
+        assert_eq!(Arc::strong_count(&witness), 2);
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        assert_eq!(Arc::strong_count(&witness), 1);
+        assert_eq!(pool.stats().cancelled, 1);
+    }
+
+    #[test]
+    fn cancel_stops_a_periodic_job() {
+        let pool = ThreadPool::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let handle = pool.execute_every(Duration::from_millis(1), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        while runs.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.cancel();
+        pool.wait_idle();
+        let after_cancel = runs.load(Ordering::SeqCst);
+        // A run that the timer thread took just before the cancel is queued
+        // by now, and skipped.
+        thread::sleep(Duration::from_millis(20));
+        pool.wait_idle();
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+        // Neither an entry nor a queued run holds the closure any more.
+        assert_eq!(Arc::strong_count(&runs), 1);
+    }
+
+    #[test]
+    fn due_jobs_are_queued_even_if_the_queue_is_full() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .queue_capacity(1)
+            .overflow_policy(OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = occupy(&pool);
+        pool.execute(|| ());
+        let (tx, rx) = mpsc::channel();
+        pool.execute_after(Duration::from_millis(1), move || tx.send(()).unwrap());
+        pool.execute_after(Duration::from_millis(1), || ());
+        // Both due jobs are queued on top of the full queue.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.stats().queued < 3 {
+            assert!(Instant::now() < deadline, "due jobs were not queued");
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(release);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        pool.wait_idle();
+    }
+
+    #[test]
+    fn next_due_of_fixed_delay_counts_from_the_end_of_the_run() {
+        let delay = Duration::from_secs(1);
+        let before = Instant::now();
+        let next = Schedule::FixedDelay(delay).next_due(before - delay * 5);
+        assert!(next >= before + delay);
+        assert!(next <= Instant::now() + delay);
+    }
+
+    #[test]
+    fn next_due_of_fixed_rate_on_time() {
+        let period = Duration::from_secs(1);
+        let due = Instant::now();
+        for missed in [MissedTicks::Burst, MissedTicks::Skip, MissedTicks::Delay] {
+            let next = Schedule::FixedRate(period, missed).next_due(due);
+            assert_eq!(next, due + period);
+        }
+    }
+
+    #[test]
+    fn next_due_of_fixed_rate_when_late() {
+        let period = Duration::from_secs(1);
+        // The run that was due now started 2.5 periods late.
+        let due = Instant::now() - period * 5 / 2;
+
+        let burst = Schedule::FixedRate(period, MissedTicks::Burst).next_due(due);
+        assert_eq!(burst, due + period);
+
+        let skip = Schedule::FixedRate(period, MissedTicks::Skip).next_due(due);
+        assert_eq!(skip, due + period * 3);
+
+        let before = Instant::now();
+        let delay = Schedule::FixedRate(period, MissedTicks::Delay).next_due(due);
+        assert!(delay >= before + period);
+        assert!(delay <= Instant::now() + period);
+    }
+
+    #[test]
+    fn periodic_runs_never_overlap() {
+        let pool = ThreadPool::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel();
+        let handle = pool.execute_periodic(
+            Schedule::FixedRate(Duration::from_millis(1), MissedTicks::Burst),
+            {
+                let running = Arc::clone(&running);
+                let runs = Arc::clone(&runs);
+                let tx = Mutex::new(tx);
+                move || {
+                    assert_eq!(running.fetch_add(1, Ordering::SeqCst), 0);
+                    thread::sleep(Duration::from_millis(3));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    if runs.fetch_add(1, Ordering::SeqCst) == 4 {
+                        tx.lock().unwrap().send(()).unwrap();
+                    }
+                }
+            },
+        );
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.cancel();
+        pool.wait_idle();
+        assert_eq!(pool.panic_count(), 0);
+    }
+}
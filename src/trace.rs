@@ -0,0 +1,98 @@
+//! Spans and events for `tracing`, emitted when the `tracing` feature is
+//! enabled. Without it, everything in here does nothing.
+
+use std::time::{Duration, Instant};
+
+/// Where a job was submitted from.
+#[derive(Clone)]
+pub(crate) struct Context {
+    /// The span that was current when the job was submitted, if job spans
+    /// are enabled at all.
+    #[cfg(feature = "tracing")]
+    parent: Option<tracing::Span>,
+}
+
+#[cfg(feature = "tracing")]
+impl Context {
+    /// Captures the span of the calling thread.
+    pub(crate) fn current() -> Context {
+        let parent = if tracing::level_enabled!(tracing::Level::DEBUG) {
+            Some(tracing::Span::current())
+        } else {
+            None
+        };
+        Context { parent }
+    }
+
+    /// Returns whether the job runs in a span, which needs to know when the
+    /// job was queued.
+    pub(crate) fn is_traced(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    /// Enters the span of the job, which runs on worker `worker`. The span is
+    /// exited when the guard is dropped.
+    pub(crate) fn enter(self, worker: usize, queued_at: Option<Instant>) -> Entered {
+        let parent = match self.parent {
+            Some(parent) => parent,
+            None => return Entered { _span: None },
+        };
+        let span = tracing::debug_span!(
+            parent: &parent,
+            "job",
+            worker,
+            queue_wait_us = tracing::field::Empty,
+        );
+        if let Some(queued_at) = queued_at {
+            span.record("queue_wait_us", queued_at.elapsed().as_micros() as u64);
+        }
+        Entered {
+            _span: Some(span.entered()),
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Context {
+    pub(crate) fn current() -> Context {
+        Context {}
+    }
+
+    pub(crate) fn is_traced(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn enter(self, _worker: usize, _queued_at: Option<Instant>) -> Entered {
+        Entered {}
+    }
+}
+
+/// The entered span of a running job, see `Context::enter`.
+pub(crate) struct Entered {
+    #[cfg(feature = "tracing")]
+    _span: Option<tracing::span::EnteredSpan>,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn worker_started(id: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(worker = id, "worker started");
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn worker_stopped(id: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(worker = id, "worker stopped");
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn resized(from: usize, to: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(from, to, "pool resized");
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn timed_out(worker: usize, timeout: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(worker, ?timeout, "job timed out");
+}
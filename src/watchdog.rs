@@ -0,0 +1,266 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::deadline::{Deadlines, Key};
+use crate::scheduler::LocalQueue;
+use crate::{handle, trace, with_handle, CancellationToken, Job, JobHandle, Shared, ThreadPool};
+
+/// Keeps the deadlines of running jobs, and reports the jobs that are still
+/// running when their deadline has passed. The thread that does this is only
+/// started when the first job with a timeout is submitted.
+pub(crate) type Watchdog = Deadlines<Watched>;
+
+/// A running job with a deadline.
+pub(crate) struct Watched {
+    /// The queue of the worker running the job.
+    local: Arc<LocalQueue>,
+    timeout: Duration,
+    /// Marks the job's handle as timed out.
+    expire: Job,
+}
+
+/// Stops watching a job when dropped, see `watch`.
+struct Watch {
+    shared: Arc<Shared>,
+    key: Key,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let watched = self.shared.watchdog.remove(self.key);
+        drop(watched);
+    }
+}
+
+impl Shared {
+    /// Reports a job that is still running past its deadline.
+    fn time_out(self: &Arc<Shared>, watched: Watched) {
+        let id = watched.local.id;
+        (watched.expire)();
+        self.metrics.timed_out.fetch_add(1, Ordering::Relaxed);
+        trace::timed_out(id, watched.timeout);
+        match &*self.timeout_handler.read().unwrap() {
+            Some(handler) => {
+                // A panic in the handler must not take down the watchdog.
+                let timeout = watched.timeout;
+                if let Err(err) = panic::catch_unwind(AssertUnwindSafe(|| handler(id, timeout))) {
+                    error!(
+                        "Timeout handler panicked for worker {}: {}",
+                        id,
+                        handle::panic_message(&*err)
+                    );
+                }
+            }
+            None => warn!(
+                "Job on worker {} is still running after its timeout of {:?}.",
+                id, watched.timeout
+            ),
+        }
+        if self.replace_stuck_workers {
+            self.replace(&watched.local);
+        }
+    }
+
+    /// Starts a worker in place of the worker that owns `local`, which is
+    /// stuck on a job. The stuck worker is retired, and stops once the job
+    /// returns, if ever.
+    fn replace(self: &Arc<Shared>, local: &LocalQueue) {
+        let mut workers = self.workers.lock().unwrap();
+        workers.reap();
+        // The worker is missing if it was retired or replaced already, or
+        // taken by a shutdown.
+        let index = match workers
+            .active
+            .iter()
+            .position(|worker| ptr::eq(&*worker.local, local))
+        {
+            Some(index) => index,
+            None => return,
+        };
+        if self.scheduler.is_shut_down() {
+            return;
+        }
+        match workers.spawn(self) {
+            Ok(id) => info!(
+                "Started worker {} in place of worker {}, which is stuck on a job.",
+                id, local.id
+            ),
+            Err(err) => {
+                error!(
+                    "Failed to start a worker in place of worker {}: {}",
+                    local.id, err
+                );
+                return;
+            }
+        }
+        let worker = workers.active.remove(index);
+        self.scheduler.retire(local);
+        // The stuck worker is joined once it has stopped.
+        workers.retired.push(worker);
+        self.metrics
+            .replaced_workers
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl ThreadPool {
+    /// Like `submit_cancellable`, but with a deadline of `timeout` from the
+    /// moment `f` starts running on a worker.
+    ///
+    /// If `f` is still running when the deadline passes, the watchdog marks
+    /// the handle as timed out, so it returns `JobError::TimedOut` right
+    /// away, and cancels the `CancellationToken` passed to `f`. The job is
+    /// reported to the timeout handler, see `set_timeout_handler`, and
+    /// counted in `PoolStats::timed_out`. A thread cannot be stopped from the
+    /// outside, so `f` keeps running until it returns, and its result is
+    /// discarded. With `ThreadPoolBuilder::replace_stuck_workers`, another
+    /// worker takes over in the meantime.
+    ///
+    /// `f` is not watched when it runs on the submitting thread because of
+    /// `OverflowPolicy::CallerRuns`.
+    ///
+    /// # Panics
+    ///
+    /// This panics in the same cases as `execute`, and if the watchdog
+    /// thread cannot be spawned.
+    pub fn submit_with_timeout<F, T>(&self, timeout: Duration, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let watchdog = &self.shared.watchdog;
+        if let Err(err) = watchdog.start(&self.shared, |shared| &shared.watchdog, Shared::time_out)
+        {
+            panic!("failed to start the watchdog: {}", err);
+        }
+        let (task, handle) = with_handle(&self.shared, f);
+        let expire = handle.expiry();
+        let shared = Arc::downgrade(&self.shared);
+        self.execute_task(task.map(|job| {
+            move || {
+                let _watch = watch(&shared, timeout, expire);
+                job();
+            }
+        }));
+        handle
+    }
+
+    /// Sets the handler that is called when a job submitted with
+    /// `submit_with_timeout` runs past its deadline. The handler receives the
+    /// id of the worker that is running the job and the job's timeout.
+    /// Without a handler, the job is logged.
+    ///
+    /// The handler runs on the watchdog thread while the job is still
+    /// running, and holds up the reports of other jobs until it returns. A
+    /// panic in the handler is logged.
+    pub fn set_timeout_handler<H>(&self, handler: H)
+    where
+        H: Fn(usize, Duration) + Send + Sync + 'static,
+    {
+        *self.shared.timeout_handler.write().unwrap() = Some(Box::new(handler));
+    }
+}
+
+/// Watches the job running on the calling worker until the returned guard
+/// is dropped. Returns `None` if the calling thread is not one of the pool's
+/// workers.
+fn watch(shared: &Weak<Shared>, timeout: Duration, expire: Job) -> Option<Watch> {
+    let shared = shared.upgrade()?;
+    let local = shared.scheduler.current_local()?;
+    let watched = Watched {
+        local,
+        timeout,
+        expire,
+    };
+    // `None` once the watchdog has stopped.
+    let key = shared.watchdog.insert(Instant::now() + timeout, watched)?;
+    Some(Watch { shared, key })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::{JobError, ThreadPool, ThreadPoolBuilder};
+
+    #[test]
+    fn stuck_job_times_out() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.set_timeout_handler(move |id, timeout| tx.send((id, timeout)).unwrap());
+        let handle = pool.submit_with_timeout(Duration::from_millis(10), |token| {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            5
+        });
+        assert!(matches!(handle.join(), Err(JobError::TimedOut)));
+        assert_eq!(rx.recv().unwrap(), (1, Duration::from_millis(10)));
+        pool.wait_idle();
+        assert_eq!(pool.stats().timed_out, 1);
+    }
+
+    #[test]
+    fn job_that_returns_in_time_is_not_reported() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.set_timeout_handler(move |id, _| tx.send(id).unwrap());
+        let handle = pool.submit_with_timeout(Duration::from_millis(20), |_| 3);
+        assert_eq!(handle.join().unwrap(), 3);
+        thread::sleep(Duration::from_millis(40));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(pool.stats().timed_out, 0);
+    }
+
+    #[test]
+    fn panicking_timeout_handler_keeps_the_watchdog_running() {
+        let pool = ThreadPool::new(1);
+        pool.set_timeout_handler(|_, _| panic!("timeout handler panicked"));
+        for _ in 0..2 {
+            let handle = pool.submit_with_timeout(Duration::from_millis(1), |token| {
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            assert!(matches!(handle.join(), Err(JobError::TimedOut)));
+        }
+        // The handle is marked before the job is counted.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.stats().timed_out < 2 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stuck_worker_is_replaced() {
+        let pool = ThreadPoolBuilder::new()
+            .thread_count(1)
+            .replace_stuck_workers(true)
+            .build()
+            .unwrap();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let handle = pool.submit_with_timeout(Duration::from_millis(10), move |_| {
+            let _ = release_rx.recv();
+        });
+        assert!(matches!(handle.join(), Err(JobError::TimedOut)));
+
+        // The stuck job still blocks worker 1, so worker 2 runs this one.
+        assert_eq!(pool.submit(|| 2).join().unwrap(), 2);
+        let stats = pool.stats();
+        assert_eq!(stats.replaced_workers, 1);
+        assert_eq!(pool.thread_count(), 1);
+        let ids: Vec<_> = stats.workers.iter().map(|worker| worker.id).collect();
+        assert_eq!(ids, [2]);
+
+        drop(release_tx);
+        pool.shutdown().unwrap();
+    }
+}
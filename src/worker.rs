@@ -0,0 +1,291 @@
+use std::cell::Cell;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use log::{debug, error};
+
+use crate::metrics::Outcome;
+use crate::scheduler::{LocalQueue, Scheduler, Task};
+use crate::{trace, Shared, ThreadPoolError};
+
+thread_local! {
+    /// The outcome of the job running on this thread, as reported by the job
+    /// itself. See `report`.
+    static OUTCOME: Cell<Outcome> = const { Cell::new(Outcome::Completed) };
+}
+
+/// A callback that receives the id of a worker, run on the worker's thread.
+pub(crate) type ThreadHook = Box<dyn Fn(usize) + Send + Sync + 'static>;
+
+/// How the threads of the workers are set up.
+#[derive(Default)]
+pub(crate) struct ThreadConfig {
+    pub(crate) name_prefix: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) on_start: Option<ThreadHook>,
+    pub(crate) on_stop: Option<ThreadHook>,
+}
+
+/// The workers of a pool.
+pub(crate) struct Workers {
+    /// The running workers, in the order they were started.
+    pub(crate) active: Vec<Worker>,
+    /// Workers that retired on their own after being idle, or were replaced
+    /// while stuck on a job, until their threads are joined.
+    pub(crate) retired: Vec<Worker>,
+    next_id: usize,
+}
+
+impl Workers {
+    pub(crate) fn new() -> Workers {
+        Workers {
+            active: Vec::new(),
+            retired: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Starts a new worker and returns its id.
+    pub(crate) fn spawn(&mut self, shared: &Arc<Shared>) -> io::Result<usize> {
+        let id = self.next_id;
+        self.active.push(Worker::new(id, Arc::clone(shared))?);
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// Joins the retired workers whose threads have terminated.
+    pub(crate) fn reap(&mut self) {
+        self.retired.retain_mut(|worker| {
+            if !worker.is_finished() {
+                return true;
+            }
+            if let Err(err) = worker.join() {
+                error!("{}", err);
+            }
+            false
+        });
+    }
+}
+
+pub(crate) struct Worker {
+    pub(crate) id: usize,
+    pub(crate) local: Arc<LocalQueue>,
+    pub(crate) thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    pub(crate) fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let config = &shared.thread_config;
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &config.name_prefix {
+            builder = builder.name(format!("{}-{}", prefix, id));
+        }
+        if let Some(stack_size) = config.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let local = shared.scheduler.register(id);
+        let thread = {
+            let local = Arc::clone(&local);
+            let shared = Arc::clone(&shared);
+            builder.spawn(move || run(id, local, shared))
+        };
+        match thread {
+            Ok(thread) => Ok(Worker {
+                id,
+                local,
+                thread: Some(thread),
+            }),
+            Err(err) => {
+                shared.scheduler.unregister(&local);
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns whether the worker's thread has terminated, or was joined.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+
+    /// Waits for the worker's thread to terminate. Does nothing if it was
+    /// joined before.
+    pub(crate) fn join(&mut self) -> Result<(), ThreadPoolError> {
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Err(_)) => Err(ThreadPoolError::WorkerPanicked { id: self.id }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Unregisters a worker when its thread stops, also when a thread callback
+/// panics. A worker that stays registered would count as searching forever,
+/// and keep the other workers from being woken up.
+struct Registration<'a> {
+    scheduler: &'a Scheduler,
+    local: &'a Arc<LocalQueue>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.scheduler.unregister(self.local);
+    }
+}
+
+fn run(id: usize, local: Arc<LocalQueue>, shared: Arc<Shared>) {
+    let scheduler = &shared.scheduler;
+    scheduler.enter(&local);
+    let registration = Registration {
+        scheduler,
+        local: &local,
+    };
+    if let Some(on_start) = &shared.thread_config.on_start {
+        on_start(id);
+    }
+    local.metrics.wake(&shared.metrics);
+    trace::worker_started(id);
+
+    let keep_alive = shared.scaling.as_ref().map(|scaling| scaling.keep_alive);
+    // When the worker last ran out of work. Spurious wakeups do not restart
+    // the keep-alive time.
+    let mut idle_since = None;
+    loop {
+        if local.is_retiring() {
+            debug!("Worker {} retired, terminating thread.", id);
+            break;
+        }
+        match scheduler.find_job(&local) {
+            Some(task) => {
+                idle_since = None;
+                shared.grow();
+                run_job(&local, task, &shared);
+                scheduler.finished(1);
+            }
+            None if scheduler.is_shut_down() => {
+                debug!("Worker {} received shutdown, terminating thread.", id);
+                break;
+            }
+            None => {
+                let deadline = keep_alive
+                    .map(|keep_alive| *idle_since.get_or_insert_with(Instant::now) + keep_alive);
+                local.metrics.sleep(&shared.metrics);
+                let woken = scheduler.wait_for_work(&local, deadline);
+                local.metrics.wake(&shared.metrics);
+                if !woken && !shared.shrink(&local) {
+                    idle_since = None;
+                }
+            }
+        }
+    }
+
+    local.metrics.sleep(&shared.metrics);
+    trace::worker_stopped(id);
+    drop(registration);
+    if let Some(on_stop) = &shared.thread_config.on_stop {
+        on_stop(id);
+    }
+}
+
+/// Runs a job taken from the queues by the worker that owns `local`, and
+/// records it in the pool's metrics.
+fn run_job(local: &LocalQueue, task: Task, shared: &Shared) {
+    let metrics = &shared.metrics;
+    let started = if metrics.timings {
+        let now = Instant::now();
+        if let Some(queued_at) = task.queued_at {
+            metrics
+                .queue_wait
+                .record(now.saturating_duration_since(queued_at));
+        }
+        Some(now)
+    } else {
+        None
+    };
+    local.metrics.job_started();
+
+    let outcome = catch(local.id, task, shared);
+
+    if let Some(started) = started {
+        metrics.execution.record(started.elapsed());
+    }
+    metrics.record(outcome);
+    local.metrics.job_finished();
+}
+
+/// Runs a job on the thread that submits it, because the queue is full and
+/// the overflow policy is `OverflowPolicy::CallerRuns`. The job is recorded
+/// like a job run by a worker, and a panic is passed to the panic handler
+/// with `CALLER` as the worker id.
+pub(crate) fn run_on_caller<F>(task: Task<F>, shared: &Shared)
+where
+    F: FnOnce(),
+{
+    let metrics = &shared.metrics;
+    let started = metrics.timings.then(Instant::now);
+    let outcome = catch(CALLER, task, shared);
+    if let Some(started) = started {
+        metrics.execution.record(started.elapsed());
+    }
+    metrics.record(outcome);
+}
+
+/// The worker id reported for jobs that run on the submitting thread, see
+/// `run_on_caller`. Workers are numbered from 1.
+const CALLER: usize = 0;
+
+/// Runs the job of `task` on behalf of worker `id`, and returns its outcome.
+/// A panic is caught and passed to the pool's panic handler.
+fn catch<F>(id: usize, task: Task<F>, shared: &Shared) -> Outcome
+where
+    F: FnOnce(),
+{
+    // A nested job must not overwrite the outcome of the job it runs in.
+    let outer = OUTCOME.with(|outcome| outcome.replace(Outcome::Completed));
+    let outcome = {
+        let _span = task.context.enter(id, task.queued_at);
+        match panic::catch_unwind(AssertUnwindSafe(task.job)) {
+            Ok(()) => OUTCOME.with(Cell::get),
+            Err(payload) => {
+                shared.handle_panic(id, payload);
+                Outcome::Panicked
+            }
+        }
+    };
+    OUTCOME.with(|current| current.set(outer));
+    outcome
+}
+
+/// Reports the outcome of the job running on the calling thread, for jobs
+/// that catch their own panics or skip their work when cancelled.
+pub(crate) fn report(outcome: Outcome) {
+    OUTCOME.with(|current| current.set(outcome));
+}
+
+/// Returns the outcome reported so far by the job running on the calling
+/// thread.
+pub(crate) fn outcome() -> Outcome {
+    OUTCOME.with(Cell::get)
+}
+
+/// Runs one queued job if the calling thread is one of the pool's workers.
+/// Used by workers that block on other jobs, so the jobs they wait for cannot
+/// be stuck behind them. Returns whether a job was run.
+pub(crate) fn help(shared: &Shared) -> bool {
+    let local = match shared.scheduler.current_local() {
+        Some(local) => local,
+        None => return false,
+    };
+    match shared.scheduler.find_job(&local) {
+        Some(task) => {
+            run_job(&local, task, shared);
+            shared.scheduler.finished(1);
+            true
+        }
+        None => false,
+    }
+}